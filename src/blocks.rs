@@ -0,0 +1,221 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use codec::{Decode, Encode};
+use jsonrpsee::core::JsonValue;
+use mongodb::bson::DateTime;
+use primitive_types::{H256, U256};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+
+use crate::pool_handler::AppContex;
+
+/// How often pending blocks are checked against the node
+pub const BLOCK_TRACK_INTERVAL: Duration = Duration::from_secs(30);
+/// How long the instance tracking the blocks keeps the lease without renewing it
+pub const BLOCK_TRACKER_LEASE: Duration = Duration::from_secs(3 * BLOCK_TRACK_INTERVAL.as_secs());
+
+/// Variant of a seal in the SCALE encoding of a header digest item
+const SEAL_DIGEST_ITEM: u8 = 5;
+/// Engine id written in the seals of the mock node, any engine is accepted
+pub const POW_ENGINE_ID: [u8; 4] = *b"pow_";
+
+/// Seal of a mined block, found in the last item of its digest
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct Seal {
+    pub difficulty: U256,
+    pub work: H256,
+    pub poscan_hash: H256,
+}
+
+impl Seal {
+    /// Decodes a hex digest log, None when it is not a seal. A digest item is its
+    /// variant, the consensus engine id and the encoded seal as a byte vector.
+    pub(crate) fn from_log(log: &str) -> Option<Seal> {
+        let item = hex::decode(log.trim_start_matches("0x")).ok()?;
+        let (variant, item) = item.split_first()?;
+        if *variant != SEAL_DIGEST_ITEM {
+            return None;
+        }
+        let mut input = item.get(POW_ENGINE_ID.len()..)?;
+        let seal = Vec::<u8>::decode(&mut input).ok()?;
+        Seal::decode(&mut &seal[..]).ok()
+    }
+
+    /// Encodes the seal as a hex digest log
+    pub(crate) fn to_log(&self) -> String {
+        format!("0x{}", hex::encode((SEAL_DIGEST_ITEM, POW_ENGINE_ID, self.encode()).encode()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockStatus {
+    /// Accepted by the node, waiting for finality
+    Pending,
+    /// Finalized with our seal
+    Confirmed,
+    /// Another block was finalized at that height
+    Orphaned,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Block {
+    pub pre_hash: H256,
+    pub parent_hash: H256,
+    /// Identifies the block, several candidates can share a pre_hash
+    pub poscan_hash: H256,
+    pub miner_wallet: String,
+    pub rig_name: String,
+    pub difficulty: U256,
+    pub timestamp: DateTime,
+    pub status: BlockStatus,
+    pub block_number: Option<u64>,
+    pub block_hash: Option<H256>,
+}
+
+fn parse_number(value: &JsonValue) -> Option<u64> {
+    value
+        .as_str()
+        .and_then(|n| u64::from_str_radix(n.trim_start_matches("0x"), 16).ok())
+}
+
+impl AppContex {
+    /// Looks for the finalized block built on top of the block's parent.
+    /// It is ours when its seal carries our poscan hash.
+    async fn check_block(&self, block: &Block) -> anyhow::Result<Option<Block>> {
+        let parent = self.node.get_header(Some(block.parent_hash)).await?;
+        let number = match parse_number(&parent["number"]) {
+            Some(number) => number + 1,
+            None => return Ok(None),
+        };
+
+        let finalized_hash = self.node.get_finalized_head().await?;
+        let finalized = self.node.get_header(Some(finalized_hash)).await?;
+        match parse_number(&finalized["number"]) {
+            Some(finalized_number) if finalized_number >= number => {}
+            _ => return Ok(None),
+        }
+
+        let block_hash = self.node.get_block_hash(number).await?;
+        let block_hash = match block_hash {
+            Some(block_hash) => block_hash,
+            None => return Ok(None),
+        };
+        let header = self.node.get_header(Some(block_hash)).await?;
+
+        let parent_matches = header["parentHash"]
+            .as_str()
+            .and_then(|h| H256::from_str(h).ok())
+            == Some(block.parent_hash);
+        let sealed_by_us = header["digest"]["logs"]
+            .as_array()
+            .map(|logs| {
+                logs.iter()
+                    .filter_map(|log| log.as_str().and_then(Seal::from_log))
+                    .any(|seal| seal.poscan_hash == block.poscan_hash)
+            })
+            .unwrap_or(false);
+
+        let status = if parent_matches && sealed_by_us {
+            BlockStatus::Confirmed
+        } else {
+            BlockStatus::Orphaned
+        };
+
+        Ok(Some(Block {
+            status,
+            block_number: Some(number),
+            block_hash: Some(block_hash),
+            ..block.clone()
+        }))
+    }
+
+    /// Polls the node until every pending block is finalized or orphaned.
+    /// Instances sharing the pool state take turns through a lease, a single
+    /// one tracks the blocks at a time.
+    pub(crate) async fn track_blocks(self: Arc<Self>) {
+        loop {
+            match self
+                .state
+                .acquire_lease("block_tracker", &self.instance_id, BLOCK_TRACKER_LEASE)
+                .await
+            {
+                Ok(true) => self.track_pending_blocks().await,
+                Ok(false) => debug!("🧱 Blocks tracked by another instance"),
+                Err(e) => error!(error = %e, "🚩 Block tracker lease could not be taken"),
+            }
+            tokio::time::sleep(BLOCK_TRACK_INTERVAL).await;
+        }
+    }
+
+    pub(crate) async fn track_pending_blocks(&self) {
+        match self.store.get_pending_blocks().await {
+            Ok(blocks) => {
+                for block in blocks {
+                    match self.check_block(&block).await {
+                        Ok(Some(block)) => {
+                            // Confirming and paying is a single step, a block
+                            // that is not pending anymore is never paid again
+                            let stored = if block.status == BlockStatus::Confirmed {
+                                self.process_payout(&block).await
+                            } else {
+                                self.store.update_block(&block).await.map(|()| true)
+                            };
+                            match stored {
+                                Ok(true) => info!(
+                                    number = block.block_number.unwrap_or_default(),
+                                    status = ?block.status,
+                                    pre_hash = ?block.pre_hash,
+                                    poscan_hash = ?block.poscan_hash,
+                                    wallet = %block.miner_wallet,
+                                    rig = %block.rig_name,
+                                    "🧱 Block status changed"
+                                ),
+                                Ok(false) => debug!(poscan_hash = ?block.poscan_hash, "🧱 Block already confirmed"),
+                                // Left pending on a failure so it is retried, the
+                            // credits already written are not paid twice
+                                Err(e) => error!(poscan_hash = ?block.poscan_hash, error = %e, "🚩 Block status could not be stored"),
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(e) => warn!(poscan_hash = ?block.poscan_hash, error = %e, "🚩 Block could not be checked"),
+                    }
+                }
+            }
+            Err(e) => error!(error = %e, "🚩 Pending blocks could not be loaded"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seal() -> Seal {
+        Seal {
+            difficulty: U256::from(100_000),
+            work: H256::repeat_byte(1),
+            poscan_hash: H256::repeat_byte(2),
+        }
+    }
+
+    #[test]
+    fn seal_is_decoded_from_its_digest_log() {
+        let log = seal().to_log();
+        assert_eq!(Seal::from_log(&log), Some(seal()));
+        assert_eq!(Seal::from_log(&log.to_uppercase().replace("0X", "0x")), Some(seal()));
+        assert_eq!(Seal::from_log(&log[..log.len() - 2]), None);
+    }
+
+    #[test]
+    fn only_seals_are_decoded() {
+        // A pre-runtime item carrying the same bytes is not a seal
+        let pre_runtime = format!("0x{}", hex::encode((6u8, *b"pscn", seal().encode()).encode()));
+        assert_eq!(Seal::from_log(&pre_runtime), None);
+        // Nor is the bare poscan hash
+        assert_eq!(Seal::from_log(&format!("0x{:x}", seal().poscan_hash)), None);
+        assert_eq!(Seal::from_log("not hex"), None);
+    }
+}
@@ -0,0 +1,233 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use primitive_types::U256;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+
+use crate::logging::LogConfig;
+use crate::payout::PayoutConfig;
+use crate::pool_handler::{DifficultyConfig, VARDIFF_MIN_SHARES};
+use crate::storage::StorageKind;
+use crate::validation::ValidationConfig;
+use crate::worker::ALGORITHMS;
+
+/// Everything the proxy can be configured with. Values missing from the
+/// file keep their defaults and command line flags override both.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) proxy: ProxyConfig,
+    pub(crate) storage: StorageConfig,
+    pub(crate) payout: PayoutConfig,
+    pub(crate) difficulty: DifficultyConfig,
+    pub(crate) validation: ValidationConfig,
+    pub(crate) log: LogConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ProxyConfig {
+    /// pool or solo
+    pub(crate) mode: String,
+    pub(crate) algo: String,
+    pub(crate) proxy_address: String,
+    /// Nodes to fail over between
+    pub(crate) node_url: Vec<String>,
+    pub(crate) pool_id: Option<String>,
+    pub(crate) stratum_address: Option<String>,
+    pub(crate) stats_address: String,
+    /// Prometheus /metrics server, metrics are not recorded without it
+    pub(crate) metrics_address: Option<String>,
+    /// How often the chain head watcher asks the node for work
+    pub(crate) work_poll_interval_ms: u64,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            mode: String::from("pool"),
+            algo: String::from("grid2d_v3.1"),
+            proxy_address: String::from("0.0.0.0:3336"),
+            node_url: vec![String::from("http://127.0.0.1:9933")],
+            pool_id: None,
+            stratum_address: None,
+            stats_address: String::from("0.0.0.0:3533"),
+            metrics_address: None,
+            work_poll_interval_ms: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct StorageConfig {
+    pub(crate) kind: StorageKind,
+    /// Taken from MONGO_URL when it is set
+    pub(crate) mongo_url: Option<String>,
+    pub(crate) db_name: String,
+    pub(crate) sqlite_path: String,
+    pub(crate) redis_url: Option<String>,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            kind: StorageKind::Mongo,
+            mongo_url: None,
+            db_name: String::from("pool-p3d"),
+            sqlite_path: String::from("p3d-pool.db"),
+            redis_url: None,
+        }
+    }
+}
+
+/// Reads a U256 written either as an integer or as a decimal string,
+/// TOML integers being too small for block rewards
+pub(crate) fn dec_u256<'de, D: Deserializer<'de>>(deserializer: D) -> Result<U256, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Number(u64),
+        Text(String),
+    }
+
+    match Value::deserialize(deserializer)? {
+        Value::Number(n) => Ok(U256::from(n)),
+        Value::Text(s) => U256::from_dec_str(&s).map_err(|e| D::Error::custom(format!("{}: {:?}", s, e))),
+    }
+}
+
+impl Config {
+    pub(crate) fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("{} could not be read: {}", path.display(), e))?;
+        toml::from_str(&content).map_err(|e| anyhow::anyhow!("{} is not valid: {}", path.display(), e))
+    }
+
+    /// Checks the whole config and reports every problem at once
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        let mut errors = Vec::new();
+        let mut check = |ok: bool, error: String| {
+            if !ok {
+                errors.push(error)
+            }
+        };
+        let is_address = |address: &str| address.parse::<SocketAddr>().is_ok();
+
+        let proxy = &self.proxy;
+        let pool_mode = proxy.mode == "pool";
+        check(
+            pool_mode || proxy.mode == "solo",
+            format!("proxy.mode must be pool or solo, not {}", proxy.mode),
+        );
+        check(
+            ALGORITHMS.contains(&proxy.algo.as_str()),
+            format!("proxy.algo must be one of {}, not {}", ALGORITHMS.join(", "), proxy.algo),
+        );
+        check(
+            is_address(&proxy.proxy_address),
+            format!("proxy.proxy_address is not an address: {}", proxy.proxy_address),
+        );
+        check(!proxy.node_url.is_empty(), String::from("proxy.node_url needs at least one node"));
+        for url in &proxy.node_url {
+            check(
+                url.starts_with("http://") || url.starts_with("https://"),
+                format!("proxy.node_url must be an http url: {}", url),
+            );
+        }
+        check(proxy.work_poll_interval_ms > 0, String::from("proxy.work_poll_interval_ms must be positive"));
+
+        let log = &self.log;
+        if let Err(e) = log.filter() {
+            check(false, e.to_string());
+        }
+        if let Some(dir) = &log.dir {
+            check(!dir.is_empty(), String::from("log.dir must not be empty"));
+        }
+
+        if pool_mode {
+            check(
+                proxy.pool_id.as_ref().map(|id| !id.is_empty()).unwrap_or(false),
+                String::from("proxy.pool_id is required in pool mode"),
+            );
+            if let Some(stratum_address) = &proxy.stratum_address {
+                check(
+                    is_address(stratum_address),
+                    format!("proxy.stratum_address is not an address: {}", stratum_address),
+                );
+            }
+            check(
+                is_address(&proxy.stats_address),
+                format!("proxy.stats_address is not an address: {}", proxy.stats_address),
+            );
+            if let Some(metrics_address) = &proxy.metrics_address {
+                check(
+                    is_address(metrics_address),
+                    format!("proxy.metrics_address is not an address: {}", metrics_address),
+                );
+            }
+
+            let storage = &self.storage;
+            match storage.kind {
+                StorageKind::Mongo => {
+                    check(
+                        storage.mongo_url.is_some(),
+                        String::from("storage.mongo_url, MONGO_URL or --mongo-url is required by the mongo storage"),
+                    );
+                    check(!storage.db_name.is_empty(), String::from("storage.db_name must not be empty"));
+                }
+                StorageKind::Sqlite => {
+                    check(!storage.sqlite_path.is_empty(), String::from("storage.sqlite_path must not be empty"))
+                }
+                StorageKind::Memory => {}
+            }
+            if let Some(redis_url) = &storage.redis_url {
+                check(
+                    redis::Client::open(redis_url.as_str()).is_ok(),
+                    format!("storage.redis_url is not a redis url: {}", redis_url),
+                );
+            }
+
+            let payout = &self.payout;
+            check(payout.pplns_window > 0, String::from("payout.pplns_window must be positive"));
+            check(
+                (0.0..=100.0).contains(&payout.pool_fee),
+                format!("payout.pool_fee must be a percent, not {}", payout.pool_fee),
+            );
+
+            let difficulty = &self.difficulty;
+            check(difficulty.block_time_sec > 0, String::from("difficulty.block_time_sec must be positive"));
+            check(
+                difficulty.adjust_window >= VARDIFF_MIN_SHARES,
+                format!("difficulty.adjust_window must be at least {}", VARDIFF_MIN_SHARES),
+            );
+            check(difficulty.clamp_factor >= 1, String::from("difficulty.clamp_factor must be at least 1"));
+            check(difficulty.damp_factor >= 1, String::from("difficulty.damp_factor must be at least 1"));
+            check(difficulty.min_difficulty > 0, String::from("difficulty.min_difficulty must be positive"));
+            check(
+                difficulty.initial_difficulty >= difficulty.min_difficulty,
+                String::from("difficulty.initial_difficulty must not be below difficulty.min_difficulty"),
+            );
+
+            let validation = &self.validation;
+            check(validation.workers != Some(0), String::from("validation.workers must be positive"));
+            check(validation.queue_size > 0, String::from("validation.queue_size must be positive"));
+        }
+
+        if !errors.is_empty() {
+            anyhow::bail!("Invalid config:\n  - {}", errors.join("\n  - "));
+        }
+        Ok(())
+    }
+
+    /// Settings that are valid but most likely a mistake
+    pub(crate) fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.proxy.mode == "pool" && self.payout.block_reward.is_zero() {
+            warnings.push(String::from("payout.block_reward is 0, confirmed blocks will pay nothing"));
+        }
+        warnings
+    }
+}
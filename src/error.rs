@@ -0,0 +1,147 @@
+use std::fmt;
+
+use jsonrpsee::core::JsonValue;
+use jsonrpsee::types::{ErrorObject, ErrorObjectOwned};
+use primitive_types::{H256, U256};
+use serde_json::json;
+
+/// Error codes returned to miners. They are part of the proxy's API
+/// and must not change once released.
+pub const BAD_HASH: i32 = -32001;
+pub const INVALID_OBJECT: i32 = -32002;
+pub const UNKNOWN_JOB: i32 = -32003;
+pub const STALE_JOB: i32 = -32004;
+pub const DUPLICATE: i32 = -32005;
+pub const LOW_DIFFICULTY: i32 = -32006;
+pub const HASH_MISMATCH: i32 = -32007;
+pub const NO_WORK: i32 = -32010;
+pub const NODE_UNAVAILABLE: i32 = -32011;
+pub const STORAGE: i32 = -32012;
+pub const BUSY: i32 = -32013;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProxyError {
+    /// The hash could not be parsed
+    BadHash(String),
+    /// The hash sent by the miner is not the hash of the object
+    HashMismatch { claimed: H256, computed: H256 },
+    /// The object could not be processed as a 3D model
+    InvalidObject,
+    /// The job was never issued or is too old to be kept
+    UnknownJob(u64),
+    /// The job was issued for a previous chain head
+    StaleJob(u64),
+    /// The object was already submitted
+    Duplicate(H256),
+    /// The object does not meet the pool difficulty
+    LowDifficulty {
+        difficulty: U256,
+        pow_difficulty: U256,
+        win_difficulty: U256,
+    },
+    /// No work was received from the node yet
+    NoWork,
+    /// The node could not be reached or sent an invalid response
+    NodeUnavailable(String),
+    /// The database could not be read or written
+    Storage(String),
+    /// Too many objects are waiting to be validated
+    Busy,
+}
+
+impl ProxyError {
+    pub(crate) fn node(e: impl fmt::Display) -> Self {
+        ProxyError::NodeUnavailable(e.to_string())
+    }
+
+    pub(crate) fn storage(e: impl fmt::Display) -> Self {
+        ProxyError::Storage(e.to_string())
+    }
+
+    pub fn code(&self) -> i32 {
+        match self {
+            ProxyError::BadHash(_) => BAD_HASH,
+            ProxyError::HashMismatch { .. } => HASH_MISMATCH,
+            ProxyError::InvalidObject => INVALID_OBJECT,
+            ProxyError::UnknownJob(_) => UNKNOWN_JOB,
+            ProxyError::StaleJob(_) => STALE_JOB,
+            ProxyError::Duplicate(_) => DUPLICATE,
+            ProxyError::LowDifficulty { .. } => LOW_DIFFICULTY,
+            ProxyError::NoWork => NO_WORK,
+            ProxyError::NodeUnavailable(_) => NODE_UNAVAILABLE,
+            ProxyError::Storage(_) => STORAGE,
+            ProxyError::Busy => BUSY,
+        }
+    }
+
+    /// Short label used in the metrics
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ProxyError::BadHash(_) => "bad_hash",
+            ProxyError::HashMismatch { .. } => "hash_mismatch",
+            ProxyError::InvalidObject => "invalid_object",
+            ProxyError::UnknownJob(_) => "unknown_job",
+            ProxyError::StaleJob(_) => "stale_job",
+            ProxyError::Duplicate(_) => "duplicate",
+            ProxyError::LowDifficulty { .. } => "low_difficulty",
+            ProxyError::NoWork => "no_work",
+            ProxyError::NodeUnavailable(_) => "node_unavailable",
+            ProxyError::Storage(_) => "storage",
+            ProxyError::Busy => "busy",
+        }
+    }
+
+    /// Structured details sent along with the message
+    pub fn data(&self) -> Option<JsonValue> {
+        match self {
+            ProxyError::LowDifficulty {
+                difficulty,
+                pow_difficulty,
+                win_difficulty,
+            } => Some(json!({
+                "difficulty": difficulty,
+                "pow_difficulty": pow_difficulty,
+                "win_difficulty": win_difficulty,
+            })),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::BadHash(e) => write!(f, "Bad hash: {}", e),
+            ProxyError::HashMismatch { claimed, computed } => {
+                write!(f, "Hash mismatch: claimed {:x} but the object hashes to {:x}", claimed, computed)
+            }
+            ProxyError::InvalidObject => write!(f, "Object could not be processed"),
+            ProxyError::UnknownJob(job_id) => write!(f, "Unknown job {}", job_id),
+            ProxyError::StaleJob(job_id) => write!(f, "Stale job {}", job_id),
+            ProxyError::Duplicate(hash) => write!(f, "Duplicate object {:x}", hash),
+            ProxyError::LowDifficulty {
+                difficulty,
+                pow_difficulty,
+                ..
+            } => write!(f, "Low difficulty {} :: Pool difficulty {}", difficulty, pow_difficulty),
+            ProxyError::NoWork => write!(f, "Waiting for work from the node"),
+            ProxyError::NodeUnavailable(e) => write!(f, "Node unavailable: {}", e),
+            ProxyError::Storage(e) => write!(f, "Storage failure: {}", e),
+            ProxyError::Busy => write!(f, "Too many objects waiting to be validated, retry later"),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+impl From<jsonrpsee::core::Error> for ProxyError {
+    fn from(e: jsonrpsee::core::Error) -> Self {
+        ProxyError::node(e)
+    }
+}
+
+impl From<ProxyError> for ErrorObjectOwned {
+    fn from(e: ProxyError) -> Self {
+        ErrorObject::owned(e.code(), e.to_string(), e.data())
+    }
+}
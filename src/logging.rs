@@ -0,0 +1,136 @@
+use std::env;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer, Registry};
+
+/// Log files are named p3d-pool-proxy.log.<date>
+pub const LOG_FILE_PREFIX: &str = "p3d-pool-proxy.log";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogFormat {
+    /// One human readable line per event
+    Text,
+    /// One JSON object per event, for log shipping
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format: {}", format)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogRotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+impl FromStr for LogRotation {
+    type Err = String;
+
+    fn from_str(rotation: &str) -> Result<Self, Self::Err> {
+        match rotation {
+            "hourly" => Ok(LogRotation::Hourly),
+            "daily" => Ok(LogRotation::Daily),
+            "never" => Ok(LogRotation::Never),
+            _ => Err(format!("Unknown log rotation: {}", rotation)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LogConfig {
+    pub(crate) format: LogFormat,
+    /// Level or filter directives such as "info,p3d_pool_proxy=debug", RUST_LOG wins when set
+    pub(crate) level: String,
+    /// No terminal clearing, banner or colors, for systemd and containers.
+    /// Implied by the json format.
+    pub(crate) headless: bool,
+    /// Directory of the rotating log files, nothing is written to disk without it
+    pub(crate) dir: Option<String>,
+    pub(crate) rotation: LogRotation,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            format: LogFormat::Text,
+            level: String::from("info"),
+            headless: false,
+            dir: None,
+            rotation: LogRotation::Daily,
+        }
+    }
+}
+
+impl LogConfig {
+    /// JSON logs are read by machines, stdout must carry nothing but events
+    pub(crate) fn is_headless(&self) -> bool {
+        self.headless || self.format == LogFormat::Json
+    }
+
+    /// Filter from RUST_LOG when it is set, from the level otherwise.
+    /// The error names the one that could not be parsed.
+    pub(crate) fn filter(&self) -> anyhow::Result<EnvFilter> {
+        match env::var(EnvFilter::DEFAULT_ENV) {
+            Ok(directives) => EnvFilter::try_new(&directives).map_err(|e| {
+                anyhow::anyhow!("{} is not a level or filter: {}: {}", EnvFilter::DEFAULT_ENV, directives, e)
+            }),
+            Err(_) => EnvFilter::try_new(&self.level)
+                .map_err(|e| anyhow::anyhow!("log.level is not a level or filter: {}: {}", self.level, e)),
+        }
+    }
+
+    fn layer<W>(&self, writer: W, ansi: bool) -> Box<dyn Layer<Registry> + Send + Sync>
+    where
+        W: for<'w> fmt::MakeWriter<'w> + Send + Sync + 'static,
+    {
+        match self.format {
+            LogFormat::Text => fmt::layer().with_writer(writer).with_ansi(ansi).boxed(),
+            LogFormat::Json => fmt::layer().json().with_writer(writer).with_ansi(false).boxed(),
+        }
+    }
+}
+
+/// Sends the events to stdout and, when a directory is set, to rotating files.
+/// The returned guard flushes the files and must be kept until exit.
+pub(crate) fn init_logging(config: &LogConfig) -> anyhow::Result<Option<WorkerGuard>> {
+    let mut layers = vec![config.layer(std::io::stdout, !config.is_headless())];
+
+    let guard = match &config.dir {
+        Some(dir) => {
+            let rotation = match config.rotation {
+                LogRotation::Hourly => Rotation::HOURLY,
+                LogRotation::Daily => Rotation::DAILY,
+                LogRotation::Never => Rotation::NEVER,
+            };
+            let (writer, guard) = tracing_appender::non_blocking(RollingFileAppender::new(rotation, dir, LOG_FILE_PREFIX));
+            layers.push(config.layer(writer, false));
+            Some(guard)
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(config.filter()?)
+        .try_init()?;
+
+    Ok(guard)
+}
@@ -1,17 +1,41 @@
 use ansi_term::{Colour, Style};
 use bip39::{Language, Mnemonic};
+use primitive_types::U256;
 use pool_handler::AppContex;
-use std::{env, process::Command, sync::Arc, thread::sleep, time::Duration};
+use solo_handler::SoloAppContex;
+use std::{env, path::PathBuf, process::Command, sync::{Arc, Mutex}, thread::sleep, time::Duration};
 use structopt::StructOpt;
 use substrate_bip39::mini_secret_from_entropy;
+use tracing::{info, warn};
 
-use crate::worker::P3dParams;
+use crate::config::Config;
+use crate::logging::{LogConfig, LogFormat, LogRotation};
+use crate::mock_node::MockChain;
+use crate::node::NodePool;
+use crate::payout::PayoutMode;
+use crate::state::{LocalState, RedisState, SharedState};
+use crate::storage::{MemoryStore, MongoStore, ShareStore, SqliteStore, StorageKind};
+use crate::worker::{P3dParams, ALGORITHMS};
 
+mod blocks;
+mod config;
+mod error;
 mod message;
+mod metrics;
+mod logging;
+mod mock_node;
+mod node;
+mod payout;
 mod pool_handler;
 mod pool_rpc;
+mod solo_handler;
+mod solo_rpc;
+mod stats;
+mod state;
 mod stats_rpc;
-mod utils;
+mod storage;
+mod stratum;
+mod validation;
 mod worker;
 
 #[derive(Debug, StructOpt)]
@@ -20,30 +44,278 @@ enum SubCommand {
     Run(RunOptions),
     #[structopt(name = "inspect", about = "Use inspect to convert seed to key")]
     Inspect(InspectOptions),
+    #[structopt(name = "mock-node", about = "Use mock-node to run a local stand-in for a 3DPass node")]
+    MockNode(MockNodeOptions),
+    #[structopt(name = "check-config", about = "Use check-config to validate the config without starting the proxy")]
+    CheckConfig(RunOptions),
 }
 
+/// Command line flags override the config file
 #[derive(Debug, StructOpt)]
 struct RunOptions {
+    #[structopt(short = "c", long = "config", parse(from_os_str))]
+    /// TOML config file
+    config: Option<PathBuf>,
+
     /// 3d hash algorithm
-    #[structopt(default_value = "grid2d_v3.1", short = "l", long = "algo")]
+    #[structopt(short = "l", long = "algo")]
     /// Mining algorithm. Supported algorithm: grid2d_v3.1
-    algo: String,
+    algo: Option<String>,
 
-    #[structopt(default_value = "0.0.0.0:3336", short = "a", long = "proxy-address")]
+    #[structopt(
+    short = "m",
+    long = "mode",
+    possible_values = &["pool", "solo"]
+    )]
+    /// Proxy mode: pool or solo
+    mode: Option<String>,
+
+    #[structopt(short = "a", long = "proxy-address")]
     /// Pool proxy address
-    proxy_address: String,
+    proxy_address: Option<String>,
 
     #[structopt(
-    default_value = "http://127.0.0.1:9933",
     short = "n",
-    long = "node-url"
+    long = "node-url",
+    use_delimiter = true
     )]
-    /// Node url
-    node_url: String,
+    /// Node urls. Repeat the flag or separate them with commas for failover
+    node_url: Vec<String>,
 
-    #[structopt(short = "p", long = "pool-id", required_if("proxy-mode", "pool"))]
-    /// Pool id
+    #[structopt(short = "p", long = "pool-id")]
+    /// Pool id, required in pool mode
     pool_id: Option<String>,
+
+    #[structopt(short = "s", long = "stratum-address")]
+    /// Stratum-style TCP server address (pool mode only)
+    stratum_address: Option<String>,
+
+    #[structopt(long = "stats-address")]
+    /// Stats server address (pool mode only)
+    stats_address: Option<String>,
+
+    #[structopt(long = "metrics-address")]
+    /// Prometheus metrics server address (pool mode only)
+    metrics_address: Option<String>,
+
+    #[structopt(
+    long = "payout-mode",
+    possible_values = &["pplns", "pps"]
+    )]
+    /// Reward scheme: pplns or pps
+    payout_mode: Option<PayoutMode>,
+
+    #[structopt(long = "pplns-window")]
+    /// Number of last shares rewarded for every confirmed block
+    pplns_window: Option<u64>,
+
+    #[structopt(long = "pool-fee")]
+    /// Pool fee in percent taken from every block reward
+    pool_fee: Option<f64>,
+
+    #[structopt(long = "block-reward", parse(try_from_str = U256::from_dec_str))]
+    /// Block reward in the smallest unit, split among the miners
+    block_reward: Option<U256>,
+
+    #[structopt(
+    long = "storage",
+    possible_values = &["mongo", "memory", "sqlite"]
+    )]
+    /// Where shares, blocks and balances are kept: mongo (MONGO_URL), memory or sqlite
+    storage: Option<StorageKind>,
+
+    #[structopt(long = "mongo-url")]
+    /// MongoDB used by the mongo storage, overrides MONGO_URL
+    mongo_url: Option<String>,
+
+    #[structopt(long = "db-name")]
+    /// MongoDB database of the pool
+    db_name: Option<String>,
+
+    #[structopt(long = "sqlite-path")]
+    /// Database file used by the sqlite storage
+    sqlite_path: Option<String>,
+
+    #[structopt(long = "redis-url")]
+    /// Redis shared by every proxy instance of the pool. Work, jobs, duplicates and vardiff stay in memory without it
+    redis_url: Option<String>,
+
+    #[structopt(long = "block-time-sec")]
+    /// Block time of the chain, also the time a rig should take to find a share
+    block_time_sec: Option<u64>,
+
+    #[structopt(long = "adjust-window")]
+    /// Number of last shares a rig's difficulty is adjusted on
+    adjust_window: Option<u64>,
+
+    #[structopt(long = "clamp-factor")]
+    /// Largest factor a rig's difficulty moves by in one adjustment
+    clamp_factor: Option<u64>,
+
+    #[structopt(long = "damp-factor")]
+    /// Damping of the difficulty adjustments, 1 for none
+    damp_factor: Option<u64>,
+
+    #[structopt(long = "initial-difficulty")]
+    /// Difficulty of a rig until it has enough shares
+    initial_difficulty: Option<u64>,
+
+    #[structopt(long = "min-difficulty")]
+    /// Lowest difficulty given to a rig
+    min_difficulty: Option<u64>,
+
+    #[structopt(long = "workers")]
+    /// Object validation threads, one per core by default
+    workers: Option<usize>,
+
+    #[structopt(long = "queue-size")]
+    /// Objects waiting for validation before new ones are rejected
+    queue_size: Option<usize>,
+
+    #[structopt(
+    long = "log-format",
+    possible_values = &["text", "json"]
+    )]
+    /// Log output: text or json
+    log_format: Option<LogFormat>,
+
+    #[structopt(long = "log-level")]
+    /// Log level or filter directives, RUST_LOG wins when set
+    log_level: Option<String>,
+
+    #[structopt(long = "headless")]
+    /// Skip clearing the terminal, the banner and colors
+    headless: bool,
+
+    #[structopt(long = "log-dir")]
+    /// Directory of the rotating log files
+    log_dir: Option<String>,
+
+    #[structopt(
+    long = "log-rotation",
+    possible_values = &["hourly", "daily", "never"]
+    )]
+    /// How often a new log file is started
+    log_rotation: Option<LogRotation>,
+}
+
+impl RunOptions {
+    /// Config file, then MONGO_URL, then the flags given
+    fn config(&self) -> anyhow::Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+
+        if let Ok(mongo_url) = env::var("MONGO_URL") {
+            config.storage.mongo_url = Some(mongo_url);
+        }
+
+        let proxy = &mut config.proxy;
+        if let Some(algo) = &self.algo {
+            proxy.algo = algo.clone();
+        }
+        if let Some(mode) = &self.mode {
+            proxy.mode = mode.clone();
+        }
+        if let Some(proxy_address) = &self.proxy_address {
+            proxy.proxy_address = proxy_address.clone();
+        }
+        if !self.node_url.is_empty() {
+            proxy.node_url = self.node_url.clone();
+        }
+        if self.pool_id.is_some() {
+            proxy.pool_id = self.pool_id.clone();
+        }
+        if self.stratum_address.is_some() {
+            proxy.stratum_address = self.stratum_address.clone();
+        }
+        if let Some(stats_address) = &self.stats_address {
+            proxy.stats_address = stats_address.clone();
+        }
+        if self.metrics_address.is_some() {
+            proxy.metrics_address = self.metrics_address.clone();
+        }
+
+        let payout = &mut config.payout;
+        if let Some(payout_mode) = self.payout_mode {
+            payout.mode = payout_mode;
+        }
+        if let Some(pplns_window) = self.pplns_window {
+            payout.pplns_window = pplns_window;
+        }
+        if let Some(pool_fee) = self.pool_fee {
+            payout.pool_fee = pool_fee;
+        }
+        if let Some(block_reward) = self.block_reward {
+            payout.block_reward = block_reward;
+        }
+
+        let storage = &mut config.storage;
+        if let Some(kind) = self.storage {
+            storage.kind = kind;
+        }
+        if self.mongo_url.is_some() {
+            storage.mongo_url = self.mongo_url.clone();
+        }
+        if let Some(db_name) = &self.db_name {
+            storage.db_name = db_name.clone();
+        }
+        if let Some(sqlite_path) = &self.sqlite_path {
+            storage.sqlite_path = sqlite_path.clone();
+        }
+        if self.redis_url.is_some() {
+            storage.redis_url = self.redis_url.clone();
+        }
+
+        let difficulty = &mut config.difficulty;
+        if let Some(block_time_sec) = self.block_time_sec {
+            difficulty.block_time_sec = block_time_sec;
+        }
+        if let Some(adjust_window) = self.adjust_window {
+            difficulty.adjust_window = adjust_window;
+        }
+        if let Some(clamp_factor) = self.clamp_factor {
+            difficulty.clamp_factor = clamp_factor;
+        }
+        if let Some(damp_factor) = self.damp_factor {
+            difficulty.damp_factor = damp_factor;
+        }
+        if let Some(initial_difficulty) = self.initial_difficulty {
+            difficulty.initial_difficulty = initial_difficulty;
+        }
+        if let Some(min_difficulty) = self.min_difficulty {
+            difficulty.min_difficulty = min_difficulty;
+        }
+
+        let validation = &mut config.validation;
+        if self.workers.is_some() {
+            validation.workers = self.workers;
+        }
+        if let Some(queue_size) = self.queue_size {
+            validation.queue_size = queue_size;
+        }
+
+        let log = &mut config.log;
+        if let Some(log_format) = self.log_format {
+            log.format = log_format;
+        }
+        if let Some(log_level) = &self.log_level {
+            log.level = log_level.clone();
+        }
+        if self.headless {
+            log.headless = true;
+        }
+        if self.log_dir.is_some() {
+            log.dir = self.log_dir.clone();
+        }
+        if let Some(log_rotation) = self.log_rotation {
+            log.rotation = log_rotation;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
 }
 
 #[derive(Debug, StructOpt)]
@@ -53,6 +325,29 @@ struct InspectOptions {
     seed: String,
 }
 
+#[derive(Debug, StructOpt)]
+struct MockNodeOptions {
+    #[structopt(default_value = "127.0.0.1:9933", short = "a", long = "address")]
+    /// Address the mock node listens on
+    address: String,
+
+    #[structopt(default_value = "grid2d_v3.1", short = "l", long = "algo", possible_values = ALGORITHMS)]
+    /// Mining algorithm used to validate pushed objects
+    algo: String,
+
+    #[structopt(default_value = "100000", long = "win-difficulty", parse(try_from_str = U256::from_dec_str))]
+    /// Network difficulty served in the mining params
+    win_difficulty: U256,
+
+    #[structopt(default_value = "1000", long = "pow-difficulty", parse(try_from_str = U256::from_dec_str))]
+    /// Pool difficulty served in the mining params
+    pow_difficulty: U256,
+
+    #[structopt(default_value = "60", long = "block-time")]
+    /// Seconds between the empty blocks imported by the mock node
+    block_time: u64,
+}
+
 #[derive(StructOpt)]
 struct Cli {
     #[structopt(subcommand)]
@@ -75,58 +370,123 @@ async fn main() -> anyhow::Result<()> {
             };
             Ok(())
         }
+        SubCommand::MockNode(opt) => {
+            let _log_guard = logging::init_logging(&LogConfig::default())?;
+            let chain = Arc::new(Mutex::new(MockChain::new(
+                P3dParams::new(opt.algo.as_str()),
+                opt.win_difficulty,
+                opt.pow_difficulty,
+            )));
+            let mock_addr = mock_node::mock_node_server(chain.clone(), opt.address.clone()).await?;
+            println!("{}", format!("🧪  Mock node      :: http://{}", mock_addr));
+
+            mock_node::simulate_blocks(chain, Duration::from_secs(opt.block_time)).await;
+            Ok(())
+        }
+        SubCommand::CheckConfig(opt) => {
+            let config = opt.config()?;
+            for warning in config.warnings() {
+                println!("⚠️  {}", warning);
+            }
+            println!("✅ Config is valid, {} mode", config.proxy.mode);
+            Ok(())
+        }
         SubCommand::Run(opt) => {
-            clear_console();
+            let config = opt.config()?;
+            let _log_guard = logging::init_logging(&config.log)?;
+            for warning in config.warnings() {
+                warn!("🚩 {}", warning);
+            }
+            let headless = config.log.is_headless();
 
             const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-            println!(
-                "{}",
-                format!(
+            if headless {
+                info!(version = VERSION, "📱 P3D Pool Proxy");
+            } else {
+                clear_console();
+
+                println!(
                     "{}",
-                    Style::new()
-                        .bold()
-                        .fg(Colour::Green)
-                        .paint(format!("📱 P3D Pool Proxy v{}\n", String::from(VERSION)))
-                )
-            );
-
-            let p3d_params = P3dParams::new(opt.algo.as_str());
-            let mongo_url = env::var("MONGO_URL").expect("MONGO_URL must be set");
-
-            let pool_ctx = AppContex::new(
-                p3d_params,
-                opt.node_url.as_str(),
-                opt.proxy_address.clone(),
-                opt.pool_id.clone().unwrap(),
-                mongo_url.as_str(),
-            )
-                .await?;
+                    format!(
+                        "{}",
+                        Style::new()
+                            .bold()
+                            .fg(Colour::Green)
+                            .paint(format!("📱 P3D Pool Proxy v{}\n", String::from(VERSION)))
+                    )
+                );
+            }
+
+            if config.proxy.mode == "solo" {
+                let nodes = Arc::new(NodePool::new(&config.proxy.node_url, config.difficulty.block_time_sec)?);
+                nodes.check_health().await;
+                tokio::spawn(nodes.clone().watch_health());
+                let solo_ctx = SoloAppContex::new(
+                    P3dParams::new(config.proxy.algo.as_str()),
+                    nodes,
+                    config.proxy.proxy_address.clone(),
+                );
+
+                let ctx = Arc::new(solo_ctx);
+                let _server_addr = worker::solo_rpc_server(ctx.clone()).await?;
+
+                announce(headless, format!("💻  Running        :: http://{}", _server_addr));
+                announce(headless, format!("🌀  Mode           :: {}", String::from("SOLO")));
+
+                return futures::future::pending().await;
+            }
+
+            // Started first so nothing is recorded before the recorder is installed
+            let metrics_addr = match config.proxy.metrics_address.clone() {
+                Some(metrics_address) => Some(metrics::run_metrics_server(metrics_address).await?),
+                None => None,
+            };
+
+            let storage = &config.storage;
+            let store: Arc<dyn ShareStore> = match storage.kind {
+                StorageKind::Mongo => {
+                    let mongo_url = storage.mongo_url.clone().unwrap_or_default();
+                    Arc::new(MongoStore::new(mongo_url.as_str(), storage.db_name.as_str()).await?)
+                }
+                StorageKind::Memory => Arc::new(MemoryStore::default()),
+                StorageKind::Sqlite => Arc::new(SqliteStore::new(storage.sqlite_path.as_str())?),
+            };
+
+            let pool_id = config.proxy.pool_id.clone().unwrap_or_default();
+            let state: Arc<dyn SharedState> = match &storage.redis_url {
+                Some(redis_url) => Arc::new(RedisState::new(redis_url, pool_id.as_str()).await?),
+                None => Arc::new(LocalState::default()),
+            };
+
+            let nodes = Arc::new(NodePool::new(&config.proxy.node_url, config.difficulty.block_time_sec)?);
+            let pool_ctx = AppContex::new(&config, nodes.clone(), store, state).await?;
 
             let ctx = Arc::new(pool_ctx);
             let _server_addr = worker::pool_rpc_server(ctx.clone()).await?;
+            nodes.check_health().await;
+            tokio::spawn(nodes.watch_health());
+            tokio::spawn(ctx.clone().watch_chain_head());
+            tokio::spawn(ctx.clone().track_blocks());
 
-            println!(
-                "{}",
-                format!("💻  Running        :: http://{}", _server_addr)
-            );
-            println!(
-                "{}",
-                format!("🌀  Mode           :: {}", String::from("POOL"))
-            );
-            println!(
-                "{}",
-                format!("🆔  Pool Id        :: {}", opt.pool_id.clone().unwrap())
-            );
+            announce(headless, format!("💻  Running        :: http://{}", _server_addr));
+            announce(headless, format!("🌀  Mode           :: {}", String::from("POOL")));
+            announce(headless, format!("🆔  Pool Id        :: {}", pool_id));
+
+            if let Some(stratum_address) = config.proxy.stratum_address.clone() {
+                let stratum_addr = stratum::stratum_server(ctx.clone(), stratum_address).await?;
+                announce(headless, format!("📡  Stratum        :: tcp://{}", stratum_addr));
+            }
 
             let stats_server_address =
-                worker::run_stats_server(String::from("0.0.0.0:3533")).await?;
+                worker::run_stats_server(config.proxy.stats_address.clone(), ctx.clone()).await?;
             let _stats_ws_address = format!("{}", stats_server_address);
 
-            println!(
-                "{}",
-                format!("💻  Stats server   :: http://{}", _stats_ws_address)
-            );
+            announce(headless, format!("💻  Stats server   :: http://{}", _stats_ws_address));
+
+            if let Some(metrics_addr) = metrics_addr {
+                announce(headless, format!("📈  Metrics        :: http://{}/metrics", metrics_addr));
+            }
             // std::thread::spawn(move || ctx.adjust_difficulty());
 
             futures::future::pending().await
@@ -134,6 +494,15 @@ async fn main() -> anyhow::Result<()> {
     }
 }
 
+/// Startup summary, printed on a terminal and logged when headless
+fn announce(headless: bool, line: String) {
+    if headless {
+        info!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
 fn clear_console() {
     if cfg!(target_os = "windows") {
         // Comando para limpiar la consola en Windows
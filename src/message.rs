@@ -1,12 +1,14 @@
 use codec::Encode;
+use mongodb::bson::DateTime;
 use serde::{Serialize, Deserialize};
 use uuid::Uuid;
 
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Message {
     pub id: String,
     pub channel: String,
+    pub timestamp: DateTime,
     pub payload: StatsPayload,
 }
 
@@ -18,6 +20,7 @@ impl Message {
         Message {
             id: Message::generate_id(),
             channel: String::from(member_id),
+            timestamp: DateTime::now(),
             payload
         }
     }
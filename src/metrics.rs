@@ -0,0 +1,101 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use ::metrics::{gauge, histogram, increment_counter};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+use crate::error::ProxyError;
+use crate::pool_handler::{ShareResult, ShareStatus};
+use crate::stats::to_f64;
+use crate::worker::MiningParams;
+
+pub const SHARES_ACCEPTED: &str = "p3d_shares_accepted_total";
+pub const SHARES_REJECTED: &str = "p3d_shares_rejected_total";
+pub const SHARES_DUPLICATE: &str = "p3d_shares_duplicate_total";
+pub const BLOCK_CANDIDATES: &str = "p3d_block_candidates_total";
+pub const P3D_PROCESS_SECONDS: &str = "p3d_process_seconds";
+pub const NODE_RPC_SECONDS: &str = "p3d_node_rpc_seconds";
+pub const NODE_RPC_ERRORS: &str = "p3d_node_rpc_errors_total";
+pub const NETWORK_DIFFICULTY: &str = "p3d_network_difficulty";
+pub const POOL_DIFFICULTY: &str = "p3d_pool_difficulty";
+pub const CONNECTED_RIGS: &str = "p3d_connected_rigs";
+pub const MONGO_WRITE_SECONDS: &str = "p3d_mongo_write_seconds";
+
+/// Buckets of every *_seconds histogram, from a fast Mongo write to a slow p3d_process
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Counts the outcome of an object pushed to the pool
+pub(crate) fn share_result(result: &Result<ShareResult, ProxyError>) {
+    match result {
+        Ok(share) => increment_counter!(
+            SHARES_ACCEPTED,
+            "block_candidate" => (share.status == ShareStatus::BlockCandidate).to_string()
+        ),
+        Err(ProxyError::Duplicate(_)) => increment_counter!(SHARES_DUPLICATE),
+        Err(e) => increment_counter!(SHARES_REJECTED, "reason" => e.reason()),
+    }
+}
+
+/// Counts a block candidate, accepted when a node took it
+pub(crate) fn block_candidate(accepted: bool) {
+    increment_counter!(BLOCK_CANDIDATES, "accepted" => accepted.to_string());
+}
+
+pub(crate) fn p3d_process(elapsed: Duration) {
+    histogram!(P3D_PROCESS_SECONDS, elapsed.as_secs_f64());
+}
+
+pub(crate) fn node_rpc(method: &'static str, elapsed: Duration, ok: bool) {
+    histogram!(NODE_RPC_SECONDS, elapsed.as_secs_f64(), "method" => method);
+    if !ok {
+        increment_counter!(NODE_RPC_ERRORS, "method" => method);
+    }
+}
+
+pub(crate) fn work(mining_params: &MiningParams) {
+    gauge!(NETWORK_DIFFICULTY, to_f64(mining_params.win_difficulty));
+    gauge!(POOL_DIFFICULTY, to_f64(mining_params.pow_difficulty));
+}
+
+pub(crate) fn connected_rigs(count: usize) {
+    gauge!(CONNECTED_RIGS, count as f64);
+}
+
+pub(crate) fn mongo_write(collection: &'static str, elapsed: Duration) {
+    histogram!(MONGO_WRITE_SECONDS, elapsed.as_secs_f64(), "collection" => collection);
+}
+
+async fn serve(handle: PrometheusHandle, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => Response::new(Body::from(handle.render())),
+        _ => {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            response
+        }
+    };
+    Ok(response)
+}
+
+/// Starts recording metrics and serves them to Prometheus on /metrics.
+/// Nothing is recorded when the server is not started.
+pub(crate) async fn run_metrics_server(address: String) -> anyhow::Result<SocketAddr> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix(String::from("_seconds")), LATENCY_BUCKETS)?
+        .install_recorder()?;
+
+    let socker_url: SocketAddr = address.parse::<SocketAddr>()?;
+    let make_service = make_service_fn(move |_| {
+        let handle = handle.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |request| serve(handle.clone(), request))) }
+    });
+    let server = Server::try_bind(&socker_url)?.serve(make_service);
+    let addr = server.local_addr();
+
+    tokio::spawn(server);
+
+    Ok(addr)
+}
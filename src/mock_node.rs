@@ -0,0 +1,374 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use codec::Encode;
+use jsonrpsee::core::JsonValue;
+use jsonrpsee::server::{RpcModule, Server};
+use jsonrpsee::types::{ErrorObject, ErrorObjectOwned};
+use primitive_types::{H256, U256};
+use serde_json::json;
+use sha3::{Digest, Sha3_256};
+use tracing::info;
+
+use crate::blocks::Seal;
+use crate::pool_handler::{get_hash_difficulty, Compute};
+use crate::worker::{p3d_obj_hash, DoubleHash, P3dParams};
+
+struct MockBlock {
+    hash: H256,
+    parent_hash: H256,
+    number: u64,
+    /// Seal of the object that mined the block, if any
+    seal: Option<Seal>,
+}
+
+impl MockBlock {
+    fn header(&self) -> JsonValue {
+        json!({
+            "parentHash": format!("0x{:x}", self.parent_hash),
+            "number": format!("0x{:x}", self.number),
+            "digest": {
+                "logs": self.seal.iter().map(Seal::to_log).collect::<Vec<_>>(),
+            },
+        })
+    }
+}
+
+fn sha3_hash(data: &[u8]) -> H256 {
+    H256::from_slice(Sha3_256::digest(data).as_slice())
+}
+
+/// A chain that only exists in memory. Blocks are instantly final, the
+/// mining params are derived from the best block, and objects meeting
+/// the network difficulty seal the next block.
+pub(crate) struct MockChain {
+    p3d_params: P3dParams,
+    win_difficulty: U256,
+    pow_difficulty: U256,
+    pub_key: ecies_ed25519::PublicKey,
+    blocks: Vec<MockBlock>,
+}
+
+impl MockChain {
+    pub(crate) fn new(p3d_params: P3dParams, win_difficulty: U256, pow_difficulty: U256) -> Self {
+        let (_, pub_key) = ecies_ed25519::generate_keypair(&mut rand::thread_rng());
+
+        MockChain {
+            p3d_params,
+            win_difficulty,
+            pow_difficulty,
+            pub_key,
+            blocks: vec![MockBlock {
+                hash: sha3_hash(b"genesis"),
+                parent_hash: H256::zero(),
+                number: 0,
+                seal: None,
+            }],
+        }
+    }
+
+    fn best(&self) -> &MockBlock {
+        &self.blocks[self.blocks.len() - 1]
+    }
+
+    /// Pre hash of the block being mined on top of the best one
+    fn pre_hash(&self) -> H256 {
+        sha3_hash(&(self.best().hash, b"pre_hash").encode())
+    }
+
+    fn block_hash(&self, number: u64) -> Option<H256> {
+        self.blocks.get(number as usize).map(|block| block.hash)
+    }
+
+    fn header(&self, hash: Option<H256>) -> Option<JsonValue> {
+        match hash {
+            Some(hash) => self.blocks.iter().find(|block| block.hash == hash).map(MockBlock::header),
+            None => Some(self.best().header()),
+        }
+    }
+
+    /// Appends a block on top of the best one
+    pub(crate) fn import_block(&mut self, seal: Option<Seal>) -> u64 {
+        let parent_hash = self.best().hash;
+        let number = self.best().number + 1;
+        self.blocks.push(MockBlock {
+            hash: sha3_hash(&(parent_hash, number, &seal).encode()),
+            parent_hash,
+            number,
+            seal,
+        });
+        number
+    }
+
+    fn mining_params(&self) -> JsonValue {
+        json!([
+            format!("0x{:x}", self.pre_hash()),
+            format!("0x{:x}", self.best().hash),
+            format!("{:x}", self.win_difficulty),
+            format!("{:x}", self.pow_difficulty),
+            hex::encode(self.pub_key.to_bytes()),
+        ])
+    }
+
+    fn meta(&self) -> JsonValue {
+        json!({
+            "pre_hash": format!("0x{:x}", self.pre_hash()),
+            "parent_hash": format!("0x{:x}", self.best().hash),
+            "difficulty": format!("0x{:x}", self.win_difficulty),
+        })
+    }
+
+    /// Validates the object like the node would and seals a block with it
+    fn push_mining_object(&mut self, obj: &str) -> Result<u64, String> {
+        let pre_hash = self.pre_hash();
+        let obj_hash = p3d_obj_hash(&self.p3d_params, pre_hash, self.best().hash, obj.as_bytes())
+            .ok_or_else(|| String::from("Object could not be processed"))?;
+        let poscan_hash = DoubleHash { pre_hash, obj_hash }.calc_hash();
+
+        let work = Compute {
+            difficulty: self.win_difficulty,
+            pre_hash,
+            poscan_hash,
+        }
+        .get_work();
+        if get_hash_difficulty(&work) < self.win_difficulty {
+            return Err(String::from("Difficulty not reached"));
+        }
+
+        let number = self.import_block(Some(Seal {
+            difficulty: self.win_difficulty,
+            work,
+            poscan_hash,
+        }));
+        info!(number, ?poscan_hash, "🧱 Mock block sealed");
+        Ok(0)
+    }
+}
+
+fn mock_error(message: impl Into<String>) -> ErrorObjectOwned {
+    ErrorObject::owned(1, message, None::<()>)
+}
+
+/// Serves the node RPCs used by the proxy on top of a MockChain
+pub(crate) async fn mock_node_server(chain: Arc<Mutex<MockChain>>, address: String) -> anyhow::Result<SocketAddr> {
+    let socker_url: SocketAddr = address.parse::<SocketAddr>()?;
+    let server = Server::builder().build(socker_url).await?;
+
+    let mut module = RpcModule::new(chain);
+
+    module.register_method("system_health", |_, _| {
+        json!({ "isSyncing": false, "peers": 1, "shouldHavePeers": true })
+    })?;
+    module.register_method("chain_getHeader", |params, chain| {
+        let hash: Option<H256> = params.sequence().optional_next()?;
+        chain
+            .lock()
+            .unwrap()
+            .header(hash)
+            .ok_or_else(|| mock_error("Unknown block"))
+    })?;
+    module.register_method("chain_getFinalizedHead", |_, chain| {
+        Ok::<_, ErrorObjectOwned>(chain.lock().unwrap().best().hash)
+    })?;
+    module.register_method("chain_getBlockHash", |params, chain| {
+        let number: u64 = params.one()?;
+        Ok::<_, ErrorObjectOwned>(chain.lock().unwrap().block_hash(number))
+    })?;
+    module.register_method("poscan_getMiningParams", |_, chain| {
+        chain.lock().unwrap().mining_params()
+    })?;
+    module.register_method("poscan_getMeta", |_, chain| chain.lock().unwrap().meta())?;
+    module.register_method("poscan_pushMiningObject", |params, chain| {
+        let (_obj_id, obj): (u64, String) = params.parse()?;
+        chain
+            .lock()
+            .unwrap()
+            .push_mining_object(&obj)
+            .map_err(mock_error)
+    })?;
+
+    let addr = server.local_addr()?;
+    let handle = server.start(module);
+
+    tokio::spawn(handle.stopped());
+
+    Ok(addr)
+}
+
+/// Imports an empty block every block time, as if someone else mined it
+pub(crate) async fn simulate_blocks(chain: Arc<Mutex<MockChain>>, block_time: Duration) {
+    loop {
+        tokio::time::sleep(block_time).await;
+        let number = chain.lock().unwrap().import_block(None);
+        info!(number, "🧱 Mock block imported");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use codec::Decode;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::error::ProxyError;
+    use crate::pool_handler::tests::context;
+    use crate::node::NodePool;
+    use crate::pool_handler::AppContex;
+    use crate::solo_handler::SoloAppContex;
+
+    const ALGO: &str = "grid2d_v3.1";
+
+    /// An ellipsoid whose radii depend on the seed, so every seed is a different object
+    fn object(seed: u32) -> String {
+        let (rx, ry, rz) = (1.0 + seed as f64 * 0.013, 0.8 + seed as f64 * 0.007, 0.6);
+        let (rings, segments) = (16, 32);
+        let mut obj = String::new();
+        for ring in 0..=rings {
+            let theta = PI * ring as f64 / rings as f64;
+            for segment in 0..segments {
+                let phi = 2.0 * PI * segment as f64 / segments as f64;
+                obj.push_str(&format!(
+                    "v {:.6} {:.6} {:.6}\n",
+                    rx * theta.sin() * phi.cos(),
+                    ry * theta.sin() * phi.sin(),
+                    rz * theta.cos()
+                ));
+            }
+        }
+        for ring in 0..rings {
+            for segment in 0..segments {
+                let a = ring * segments + segment + 1;
+                let b = ring * segments + (segment + 1) % segments + 1;
+                let (c, d) = (a + segments, b + segments);
+                obj.push_str(&format!("f {} {} {}\nf {} {} {}\n", a, c, b, b, c, d));
+            }
+        }
+        obj
+    }
+
+    /// Work served to the rig: (pre_hash, parent_hash, pow_difficulty, job_id)
+    async fn get_work(ctx: &AppContex) -> (H256, H256, U256, u64) {
+        let encoded = ctx.get_mining_params(String::from("wallet"), String::from("rig")).await.unwrap();
+        let (pre_hash, parent_hash, _win_difficulty, pow_difficulty, _pub_key, job_id) =
+            <(H256, H256, U256, U256, U256, u64)>::decode(&mut &hex::decode(encoded).unwrap()[..]).unwrap();
+        (pre_hash, parent_hash, pow_difficulty, job_id)
+    }
+
+    async fn submit(ctx: &AppContex, seed: u32, work: (H256, H256, U256, u64)) -> Result<(), ProxyError> {
+        let (pre_hash, parent_hash, _, job_id) = work;
+        let obj = object(seed);
+        let hash = p3d_obj_hash(&P3dParams::new(ALGO), pre_hash, parent_hash, obj.as_bytes()).unwrap();
+        ctx.push_to_pool(format!("{:?}", hash), obj, String::from("wallet"), String::from("rig"), job_id)
+            .await
+            .map(|_| ())
+    }
+
+    /// Waits for the chain head watcher to serve work on top of this pre_hash
+    async fn wait_for_work(ctx: &AppContex, previous: Option<H256>) {
+        for _ in 0..100 {
+            let pre_hash = ctx.current_work().await.unwrap().map(|mp| mp.pre_hash);
+            if pre_hash.is_some() && pre_hash != previous {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("No new work from the mock node");
+    }
+
+    #[tokio::test]
+    async fn get_work_submit_and_vardiff() {
+        // Every object meets the pool difficulty and none seals a block
+        let chain = Arc::new(Mutex::new(MockChain::new(P3dParams::new(ALGO), U256::max_value(), U256::one())));
+        let mock_addr = mock_node_server(chain.clone(), String::from("127.0.0.1:0")).await.unwrap();
+
+        let mut config = Config::default();
+        config.proxy.algo = String::from(ALGO);
+        config.proxy.node_url = vec![format!("http://{}", mock_addr)];
+        config.proxy.work_poll_interval_ms = 10;
+        config.difficulty.initial_difficulty = 1;
+        config.difficulty.min_difficulty = 1;
+        let ctx = Arc::new(context(&config).await);
+        tokio::spawn(ctx.clone().watch_chain_head());
+        wait_for_work(&ctx, None).await;
+
+        let work = get_work(&ctx).await;
+        assert_eq!(work.2, U256::one());
+        submit(&ctx, 0, work).await.unwrap();
+        assert!(matches!(submit(&ctx, 0, work).await, Err(ProxyError::Duplicate(_))));
+        assert!(matches!(
+            submit(&ctx, 1, (work.0, work.1, work.2, work.3 + 100)).await,
+            Err(ProxyError::UnknownJob(_))
+        ));
+
+        // Enough shares in a row for vardiff to raise the rig's difficulty
+        for seed in 1..=6 {
+            submit(&ctx, seed, work).await.unwrap();
+        }
+        let harder = get_work(&ctx).await;
+        assert!(harder.2 > work.2);
+        assert_ne!(harder.3, work.3);
+
+        // Jobs of the previous chain head are stale
+        chain.lock().unwrap().import_block(None);
+        wait_for_work(&ctx, Some(work.0)).await;
+        assert!(matches!(submit(&ctx, 7, work).await, Err(ProxyError::StaleJob(_))));
+        // Back at difficulty 1 any object meets it, so the new job is accepted
+        ctx.state.set_rig_difficulty("wallet", "rig", U256::one()).await.unwrap();
+        let current = get_work(&ctx).await;
+        assert_eq!(current.2, U256::one());
+        submit(&ctx, 7, current).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn mined_block_is_confirmed() {
+        // Every object seals a block
+        let chain = Arc::new(Mutex::new(MockChain::new(P3dParams::new(ALGO), U256::one(), U256::one())));
+        let mock_addr = mock_node_server(chain.clone(), String::from("127.0.0.1:0")).await.unwrap();
+
+        let mut config = Config::default();
+        config.proxy.algo = String::from(ALGO);
+        config.proxy.node_url = vec![format!("http://{}", mock_addr)];
+        config.proxy.work_poll_interval_ms = 10;
+        config.difficulty.initial_difficulty = 1;
+        config.difficulty.min_difficulty = 1;
+        config.payout.block_reward = U256::from(1_000_000);
+        let ctx = Arc::new(context(&config).await);
+        tokio::spawn(ctx.clone().watch_chain_head());
+        wait_for_work(&ctx, None).await;
+
+        submit(&ctx, 0, get_work(&ctx).await).await.unwrap();
+        assert_eq!(ctx.store.get_pending_blocks().await.unwrap().len(), 1);
+
+        // The sealed block is final at once, its seal carries our poscan hash
+        ctx.track_pending_blocks().await;
+        assert!(ctx.store.get_pending_blocks().await.unwrap().is_empty());
+        assert_eq!(ctx.store.get_unpaid_balance("wallet").await.unwrap(), ctx.payout.net_reward());
+    }
+
+    #[tokio::test]
+    async fn solo_object_is_pushed_to_node() {
+        // Every object seals a block
+        let chain = Arc::new(Mutex::new(MockChain::new(P3dParams::new(ALGO), U256::one(), U256::one())));
+        let mock_addr = mock_node_server(chain.clone(), String::from("127.0.0.1:0")).await.unwrap();
+        let node = NodePool::new(&[format!("http://{}", mock_addr)], 60).unwrap();
+        let ctx = SoloAppContex::new(P3dParams::new(ALGO), Arc::new(node), String::new());
+
+        ctx.get_meta().await.unwrap();
+        let (pre_hash, parent_hash) = {
+            let chain = chain.lock().unwrap();
+            (chain.pre_hash(), chain.best().hash)
+        };
+        let obj = object(0);
+        let hash = p3d_obj_hash(&P3dParams::new(ALGO), pre_hash, parent_hash, obj.as_bytes()).unwrap();
+
+        let mismatch = ctx.push_to_node(format!("{:?}", H256::repeat_byte(1)), obj.clone()).await;
+        assert!(matches!(mismatch, Err(ProxyError::HashMismatch { computed, .. }) if computed == hash));
+        assert_eq!(chain.lock().unwrap().best().number, 0);
+
+        ctx.push_to_node(format!("{:?}", hash), obj).await.unwrap();
+        assert_eq!(chain.lock().unwrap().best().number, 1);
+    }
+}
@@ -0,0 +1,269 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+use jsonrpsee::core::client::ClientT;
+use jsonrpsee::core::params::ArrayParams;
+use jsonrpsee::core::{async_trait, Error, JsonValue};
+use jsonrpsee::http_client::{HttpClient, HttpClientBuilder};
+use jsonrpsee::rpc_params;
+use primitive_types::H256;
+use serde::de::DeserializeOwned;
+use tracing::warn;
+
+use crate::metrics;
+
+/// How often every node is health checked
+pub const NODE_HEALTH_INTERVAL: Duration = Duration::from_secs(5);
+/// A node whose best block has not moved for this many block times is considered stalled
+pub const NODE_HEAD_TIMEOUT_BLOCKS: u64 = 5;
+/// Blocks a node may lag behind the best known head and still be healthy
+pub const NODE_MAX_LAG: u64 = 2;
+/// Longest a node may take to answer before the next one is tried
+pub const NODE_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The node RPCs the pool relies on
+#[async_trait]
+pub(crate) trait NodeClient: Send + Sync {
+    /// poscan_getMiningParams: [pre_hash, parent_hash, win_difficulty, pow_difficulty, pub_key]
+    async fn get_mining_params(&self, pool_id: &str) -> Result<JsonValue, Error>;
+
+    /// poscan_getMeta: {pre_hash, parent_hash, difficulty} of the block mined solo
+    async fn get_meta(&self) -> Result<JsonValue, Error>;
+
+    /// poscan_pushMiningObject, 0 when the object is accepted
+    async fn push_mining_object(&self, obj: &str) -> Result<u64, Error>;
+
+    /// Header of the given block, or of the best block
+    async fn get_header(&self, hash: Option<H256>) -> Result<JsonValue, Error>;
+
+    async fn get_finalized_head(&self) -> Result<H256, Error>;
+
+    async fn get_block_hash(&self, number: u64) -> Result<Option<H256>, Error>;
+}
+
+#[derive(Clone, Default)]
+struct NodeHealth {
+    reachable: bool,
+    syncing: bool,
+    best_number: u64,
+    head_changed_at: Option<Instant>,
+    latency: Duration,
+}
+
+pub(crate) struct Node {
+    pub(crate) url: String,
+    pub(crate) client: HttpClient,
+    health: Mutex<NodeHealth>,
+}
+
+impl Node {
+    /// Every RPC sent to the node goes through here to be timed
+    async fn request<R: DeserializeOwned>(&self, method: &'static str, params: ArrayParams) -> Result<R, Error> {
+        let started = Instant::now();
+        let response = self.client.request(method, params).await;
+        metrics::node_rpc(method, started.elapsed(), response.is_ok());
+        response
+    }
+
+    async fn check(&self) -> Result<(bool, u64), Error> {
+        let system_health: JsonValue = self.request("system_health", rpc_params![]).await?;
+        let header: JsonValue = self.request("chain_getHeader", rpc_params![]).await?;
+
+        let syncing = system_health["isSyncing"].as_bool().unwrap_or(true);
+        let best_number = header["number"]
+            .as_str()
+            .and_then(|n| u64::from_str_radix(n.trim_start_matches("0x"), 16).ok())
+            .unwrap_or_default();
+
+        Ok((syncing, best_number))
+    }
+
+    async fn update_health(&self) {
+        let started = Instant::now();
+        let check = self.check().await;
+        let latency = started.elapsed();
+
+        let mut health = self.health.lock().unwrap();
+        let was_healthy = health.reachable && !health.syncing;
+        match check {
+            Ok((syncing, best_number)) => {
+                if best_number != health.best_number || health.head_changed_at.is_none() {
+                    health.head_changed_at = Some(Instant::now());
+                }
+                health.reachable = true;
+                health.syncing = syncing;
+                health.best_number = best_number;
+                health.latency = latency;
+            }
+            Err(e) => {
+                if was_healthy {
+                    warn!(node = %self.url, error = %e, "🚩 Node is unreachable");
+                }
+                health.reachable = false;
+            }
+        }
+    }
+}
+
+/// The nodes the proxy talks to. Work is fetched from the healthiest one
+/// and block candidates are broadcast to all the healthy ones.
+pub(crate) struct NodePool {
+    nodes: Vec<Node>,
+    head_timeout: Duration,
+}
+
+impl NodePool {
+    pub(crate) fn new(node_urls: &[String], block_time_sec: u64) -> anyhow::Result<Self> {
+        let mut nodes = Vec::new();
+        for url in node_urls {
+            nodes.push(Node {
+                url: url.clone(),
+                client: HttpClientBuilder::default()
+                    .request_timeout(NODE_REQUEST_TIMEOUT)
+                    .build(url)?,
+                health: Mutex::new(NodeHealth::default()),
+            });
+        }
+
+        if nodes.is_empty() {
+            anyhow::bail!("At least one node url is required");
+        }
+
+        Ok(NodePool {
+            nodes,
+            head_timeout: Duration::from_secs(NODE_HEAD_TIMEOUT_BLOCKS * block_time_sec),
+        })
+    }
+
+    fn is_healthy(&self, health: &NodeHealth, best_known: u64) -> bool {
+        health.reachable
+            && !health.syncing
+            && health.best_number + NODE_MAX_LAG >= best_known
+            && health
+                .head_changed_at
+                .map(|changed_at| changed_at.elapsed() < self.head_timeout)
+                .unwrap_or(false)
+    }
+
+    fn healthy_nodes(&self) -> Vec<(&Node, NodeHealth)> {
+        let healths: Vec<NodeHealth> = self
+            .nodes
+            .iter()
+            .map(|node| node.health.lock().unwrap().clone())
+            .collect();
+        let best_known = healths.iter().map(|h| h.best_number).max().unwrap_or_default();
+
+        self.nodes
+            .iter()
+            .zip(healths)
+            .filter(|(_, health)| self.is_healthy(health, best_known))
+            .collect()
+    }
+
+    /// Healthy nodes, healthiest first: freshest head, then lowest latency.
+    /// Every node, in the configured order, when none is healthy.
+    fn ranked(&self) -> Vec<&Node> {
+        let mut healthy = self.healthy_nodes();
+        if healthy.is_empty() {
+            return self.nodes.iter().collect();
+        }
+
+        healthy.sort_by(|(_, a), (_, b)| {
+            b.best_number
+                .cmp(&a.best_number)
+                .then(a.latency.cmp(&b.latency))
+        });
+        healthy.into_iter().map(|(node, _)| node).collect()
+    }
+
+    /// Sends the request to the healthiest node, and to the next one whenever it fails
+    async fn request<R: DeserializeOwned>(&self, method: &'static str, params: ArrayParams) -> Result<R, Error> {
+        let mut result = Err(Error::Custom("No node to send the request to".into()));
+        for node in self.ranked() {
+            result = node.request(method, params.clone()).await;
+            match &result {
+                Ok(_) => break,
+                Err(e) => warn!(node = %node.url, method, error = %e, "🚩 Node request failed"),
+            }
+        }
+        result
+    }
+
+    /// Healthy nodes, or every node when none is known to be healthy
+    pub(crate) fn healthy(&self) -> Vec<&Node> {
+        let healthy: Vec<&Node> = self.healthy_nodes().into_iter().map(|(node, _)| node).collect();
+        if healthy.is_empty() {
+            self.nodes.iter().collect()
+        } else {
+            healthy
+        }
+    }
+
+    /// Checks every node at once, a stalled one does not hold the others back
+    pub(crate) async fn check_health(&self) {
+        join_all(self.nodes.iter().map(Node::update_health)).await;
+    }
+
+    /// Keeps the health of every node up to date
+    pub(crate) async fn watch_health(self: Arc<Self>) {
+        loop {
+            self.check_health().await;
+            tokio::time::sleep(NODE_HEALTH_INTERVAL).await;
+        }
+    }
+}
+
+#[async_trait]
+impl NodeClient for NodePool {
+    async fn get_mining_params(&self, pool_id: &str) -> Result<JsonValue, Error> {
+        self.request("poscan_getMiningParams", rpc_params![serde_json::json!(pool_id)])
+            .await
+    }
+
+    async fn get_meta(&self) -> Result<JsonValue, Error> {
+        self.request("poscan_getMeta", rpc_params![]).await
+    }
+
+    /// Pushes an object to every healthy node. It is accepted when any node accepts it.
+    async fn push_mining_object(&self, obj: &str) -> Result<u64, Error> {
+        let nodes = self.healthy();
+        let responses = join_all(nodes.iter().map(|node| {
+            node.request::<u64>(
+                "poscan_pushMiningObject",
+                rpc_params![serde_json::json!(1), serde_json::json!(obj)],
+            )
+        }))
+        .await;
+
+        let mut result = Err(Error::Custom("No node to push the object to".into()));
+        for (node, response) in nodes.iter().zip(responses) {
+            match response {
+                Ok(0) => return Ok(0),
+                Ok(code) => result = Ok(code),
+                Err(e) => {
+                    warn!(node = %node.url, error = %e, "🚩 Node rejected the object");
+                    if result.is_err() {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    async fn get_header(&self, hash: Option<H256>) -> Result<JsonValue, Error> {
+        match hash {
+            Some(hash) => self.request("chain_getHeader", rpc_params![hash]).await,
+            None => self.request("chain_getHeader", rpc_params![]).await,
+        }
+    }
+
+    async fn get_finalized_head(&self) -> Result<H256, Error> {
+        self.request("chain_getFinalizedHead", rpc_params![]).await
+    }
+
+    async fn get_block_hash(&self, number: u64) -> Result<Option<H256>, Error> {
+        self.request("chain_getBlockHash", rpc_params![number]).await
+    }
+}
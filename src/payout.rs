@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use mongodb::bson::DateTime;
+use primitive_types::{H256, U256};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::blocks::Block;
+use crate::config::dec_u256;
+use crate::pool_handler::{AppContex, Share};
+
+/// Fees are applied in basis points to keep the math in integers
+pub const FEE_BASIS_POINTS: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum PayoutMode {
+    /// Confirmed blocks are split among the last N shares, a share
+    /// is rewarded by every block found while it is in the window
+    Pplns,
+    /// Every accepted share is credited when it is submitted
+    Pps,
+}
+
+impl FromStr for PayoutMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "pplns" => Ok(PayoutMode::Pplns),
+            "pps" => Ok(PayoutMode::Pps),
+            _ => Err(format!("Unknown payout mode: {}", mode)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct PayoutConfig {
+    pub(crate) mode: PayoutMode,
+    /// Number of shares rewarded for every confirmed block
+    pub(crate) pplns_window: u64,
+    /// Pool fee in percent
+    pub(crate) pool_fee: f64,
+    /// Block reward in the smallest unit
+    #[serde(deserialize_with = "dec_u256")]
+    pub(crate) block_reward: U256,
+}
+
+impl Default for PayoutConfig {
+    fn default() -> Self {
+        PayoutConfig {
+            mode: PayoutMode::Pplns,
+            pplns_window: 10_000,
+            pool_fee: 1.0,
+            block_reward: U256::zero(),
+        }
+    }
+}
+
+impl PayoutConfig {
+    /// Block reward left for the miners once the pool fee is taken
+    pub(crate) fn net_reward(&self) -> U256 {
+        let fee = (self.pool_fee * 100.0).round().clamp(0.0, FEE_BASIS_POINTS as f64) as u64;
+        self.block_reward * U256::from(FEE_BASIS_POINTS - fee) / U256::from(FEE_BASIS_POINTS)
+    }
+}
+
+/// Credit owed to a wallet, written to the balances ledger
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Balance {
+    pub miner_wallet: String,
+    pub amount: U256,
+    /// Block the credit comes from
+    pub pre_hash: H256,
+    /// Rewarded block candidate, or rewarded share in PPS. A wallet is
+    /// credited at most once for each of them, so a credit can be written again.
+    pub poscan_hash: H256,
+    pub timestamp: DateTime,
+    pub paid: bool,
+}
+
+/// Splits the reward among the wallets of the shares, weighted by difficulty
+pub(crate) fn pplns_balances(shares: &[Share], reward: U256, block: &Block) -> Vec<Balance> {
+    let mut weights: HashMap<String, U256> = HashMap::new();
+    let mut total = U256::zero();
+    for share in shares.iter() {
+        *weights.entry(share.miner_wallet.clone()).or_default() += share.difficulty;
+        total += share.difficulty;
+    }
+
+    if total.is_zero() {
+        return Vec::new();
+    }
+
+    let timestamp = DateTime::now();
+    weights
+        .into_iter()
+        .map(|(miner_wallet, weight)| Balance {
+            miner_wallet,
+            amount: reward * weight / total,
+            pre_hash: block.pre_hash,
+            poscan_hash: block.poscan_hash,
+            timestamp,
+            paid: false,
+        })
+        .collect()
+}
+
+impl AppContex {
+    /// Confirms the block and rewards it according to the payout mode, in one
+    /// store step that only goes through while the block is pending. False when
+    /// it was already confirmed, it is never paid twice.
+    pub(crate) async fn process_payout(&self, block: &Block) -> anyhow::Result<bool> {
+        match self.payout.mode {
+            PayoutMode::Pplns => self.process_pplns_payout(block).await,
+            // Shares were already paid when they were submitted
+            PayoutMode::Pps => self.store.confirm_block(block, Vec::new(), &[]).await,
+        }
+    }
+
+    /// Credit of a PPS share, its expected value: the block reward scaled by
+    /// the share difficulty relative to the network difficulty. None when
+    /// it is worth nothing, with a zero block reward for instance.
+    pub(crate) fn pps_credit(
+        &self,
+        miner_wallet: String,
+        pre_hash: H256,
+        poscan_hash: H256,
+        difficulty: U256,
+        win_difficulty: U256,
+    ) -> Option<Balance> {
+        if win_difficulty.is_zero() {
+            return None;
+        }
+        let amount = self.payout.net_reward() * difficulty / win_difficulty;
+        if amount.is_zero() {
+            return None;
+        }
+        Some(Balance {
+            miner_wallet,
+            amount,
+            pre_hash,
+            poscan_hash,
+            timestamp: DateTime::now(),
+            paid: false,
+        })
+    }
+
+    /// Splits the block reward among the last N shares found up to the block,
+    /// weighted by difficulty. Shares already rewarded by an earlier block
+    /// count again as long as they are in the window.
+    pub(crate) async fn process_pplns_payout(&self, block: &Block) -> anyhow::Result<bool> {
+        let shares = self
+            .store
+            .get_pplns_shares(block.timestamp, self.payout.pplns_window)
+            .await?;
+
+        let reward = self.payout.net_reward();
+        let balances = pplns_balances(&shares, reward, block);
+        if balances.is_empty() {
+            warn!(poscan_hash = ?block.poscan_hash, "🚩 No shares to reward for block");
+        }
+        let wallets = balances.len();
+
+        let confirmed = self.store.confirm_block(block, balances, &shares).await?;
+        if confirmed {
+            info!(
+                poscan_hash = ?block.poscan_hash,
+                shares = shares.len(),
+                wallets,
+                %reward,
+                "💰 PPLNS payout"
+            );
+        }
+
+        Ok(confirmed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::BlockStatus;
+    use crate::config::Config;
+    use crate::pool_handler::tests::{context, share};
+
+    fn pending_block() -> Block {
+        Block {
+            pre_hash: H256::repeat_byte(1),
+            parent_hash: H256::repeat_byte(2),
+            poscan_hash: H256::repeat_byte(3),
+            miner_wallet: String::from("wallet"),
+            rig_name: String::from("rig"),
+            difficulty: U256::from(1000),
+            timestamp: DateTime::now(),
+            status: BlockStatus::Pending,
+            block_number: Some(1),
+            block_hash: Some(H256::repeat_byte(4)),
+        }
+    }
+
+    fn pplns_config() -> Config {
+        let mut config = Config::default();
+        config.payout.block_reward = U256::from(1_000_000);
+        config
+    }
+
+    #[test]
+    fn net_reward_takes_the_pool_fee() {
+        let mut payout = PayoutConfig {
+            block_reward: U256::from(1_000_000),
+            ..Default::default()
+        };
+        assert_eq!(payout.net_reward(), U256::from(990_000));
+
+        payout.pool_fee = 0.0;
+        assert_eq!(payout.net_reward(), U256::from(1_000_000));
+        payout.pool_fee = 2.5;
+        assert_eq!(payout.net_reward(), U256::from(975_000));
+        payout.pool_fee = 100.0;
+        assert_eq!(payout.net_reward(), U256::zero());
+    }
+
+    #[test]
+    fn pplns_splits_by_difficulty() {
+        let shares = vec![
+            share("alice", "rig", 3000, 10),
+            share("bob", "rig", 2000, 10),
+            share("alice", "other", 1000, 20),
+        ];
+        let block = pending_block();
+        let mut balances = pplns_balances(&shares, U256::from(1000), &block);
+        balances.sort_by(|a, b| a.miner_wallet.cmp(&b.miner_wallet));
+
+        let amounts: Vec<_> = balances.iter().map(|b| (b.miner_wallet.as_str(), b.amount)).collect();
+        assert_eq!(amounts, vec![("alice", U256::from(750)), ("bob", U256::from(250))]);
+        assert!(balances
+            .iter()
+            .all(|b| b.pre_hash == block.pre_hash && b.poscan_hash == block.poscan_hash && !b.paid));
+
+        assert!(pplns_balances(&[], U256::from(1000), &block).is_empty());
+    }
+
+    #[tokio::test]
+    async fn pps_credit_skips_worthless_shares() {
+        let ctx = context(&pplns_config()).await;
+        let credit = ctx
+            .pps_credit(String::from("wallet"), H256::repeat_byte(1), H256::repeat_byte(2), U256::from(10), U256::from(1000))
+            .unwrap();
+        assert_eq!(credit.amount, ctx.payout.net_reward() / 100);
+        assert_eq!(credit.poscan_hash, H256::repeat_byte(2));
+
+        assert!(ctx
+            .pps_credit(String::from("wallet"), H256::zero(), H256::zero(), U256::from(10), U256::zero())
+            .is_none());
+        let ctx = context(&Config::default()).await;
+        assert!(ctx
+            .pps_credit(String::from("wallet"), H256::zero(), H256::zero(), U256::from(10), U256::from(1000))
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn block_is_paid_once() {
+        let ctx = context(&pplns_config()).await;
+        ctx.store.insert_share(share("wallet", "rig", 1000, 10)).await.unwrap();
+        let block = pending_block();
+        ctx.store.insert_block(block.clone()).await.unwrap();
+
+        let confirmed = Block {
+            status: BlockStatus::Confirmed,
+            ..block
+        };
+        assert!(ctx.process_payout(&confirmed).await.unwrap());
+        let paid = ctx.store.get_unpaid_balance("wallet").await.unwrap();
+        assert_eq!(paid, ctx.payout.net_reward());
+
+        // A later share would be picked up by a second payout
+        ctx.store.insert_share(share("wallet", "rig", 500, 10)).await.unwrap();
+        assert!(!ctx.process_payout(&confirmed).await.unwrap());
+        assert_eq!(ctx.store.get_unpaid_balance("wallet").await.unwrap(), paid);
+        assert!(ctx.store.get_pending_blocks().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn pplns_windows_overlap() {
+        let mut config = pplns_config();
+        config.payout.pplns_window = 2;
+        let ctx = context(&config).await;
+        let reward = ctx.payout.net_reward();
+
+        ctx.store.insert_share(share("alice", "rig", 3000, 10)).await.unwrap();
+        let first = Block {
+            timestamp: DateTime::from_millis(DateTime::now().timestamp_millis() - 2500),
+            ..pending_block()
+        };
+        ctx.store.insert_block(first.clone()).await.unwrap();
+        let first = Block {
+            status: BlockStatus::Confirmed,
+            ..first
+        };
+        assert!(ctx.process_payout(&first).await.unwrap());
+
+        // Alice's share is rewarded again, until two newer shares push it out
+        ctx.store.insert_share(share("bob", "rig", 2000, 10)).await.unwrap();
+        let second = Block {
+            poscan_hash: H256::repeat_byte(5),
+            timestamp: DateTime::from_millis(DateTime::now().timestamp_millis() - 1500),
+            ..pending_block()
+        };
+        ctx.store.insert_block(second.clone()).await.unwrap();
+        let second = Block {
+            status: BlockStatus::Confirmed,
+            ..second
+        };
+        assert!(ctx.process_payout(&second).await.unwrap());
+
+        ctx.store.insert_share(share("bob", "rig", 1000, 10)).await.unwrap();
+        let third = Block {
+            poscan_hash: H256::repeat_byte(6),
+            ..pending_block()
+        };
+        ctx.store.insert_block(third.clone()).await.unwrap();
+        let third = Block {
+            status: BlockStatus::Confirmed,
+            ..third
+        };
+        assert!(ctx.process_payout(&third).await.unwrap());
+
+        assert_eq!(ctx.store.get_unpaid_balance("alice").await.unwrap(), reward + reward / 2);
+        assert_eq!(ctx.store.get_unpaid_balance("bob").await.unwrap(), reward / 2 + reward);
+    }
+
+    #[tokio::test]
+    async fn retried_payout_credits_once() {
+        let ctx = context(&pplns_config()).await;
+        ctx.store.insert_share(share("wallet", "rig", 1000, 10)).await.unwrap();
+        let block = pending_block();
+        ctx.store.insert_block(block.clone()).await.unwrap();
+
+        // A pass that wrote the credits and failed before confirming the block
+        let shares = ctx.store.get_pplns_shares(block.timestamp, ctx.payout.pplns_window).await.unwrap();
+        let balances = pplns_balances(&shares, ctx.payout.net_reward(), &block);
+        ctx.store.credit_balances(balances).await.unwrap();
+        assert_eq!(ctx.store.get_pending_blocks().await.unwrap().len(), 1);
+
+        let confirmed = Block {
+            status: BlockStatus::Confirmed,
+            ..block
+        };
+        assert!(ctx.process_payout(&confirmed).await.unwrap());
+        assert_eq!(ctx.store.get_unpaid_balance("wallet").await.unwrap(), ctx.payout.net_reward());
+    }
+
+    #[tokio::test]
+    async fn vardiff_survives_pplns_payout() {
+        let ctx = context(&pplns_config()).await;
+        for i in 0..10 {
+            ctx.store.insert_share(share("wallet", "rig", (10 - i) * 1000, 2_000_000)).await.unwrap();
+        }
+        let block = pending_block();
+        ctx.store.insert_block(block.clone()).await.unwrap();
+        let confirmed = Block {
+            status: BlockStatus::Confirmed,
+            ..block
+        };
+        assert!(ctx.process_payout(&confirmed).await.unwrap());
+
+        ctx.adjust_difficulty(String::from("wallet"), String::from("rig")).await.unwrap();
+        let difficulty = ctx.state.get_rig_difficulty("wallet", "rig").await.unwrap();
+        assert!(difficulty > Some(U256::from(ctx.difficulty.initial_difficulty)));
+    }
+}
@@ -1,37 +1,35 @@
 use std::cmp::{max, min};
+use std::collections::HashMap;
 use codec::Encode;
-use jsonrpsee::core::client::ClientT;
-use jsonrpsee::core::{Error, JsonValue};
-use jsonrpsee::http_client::{HttpClient, HttpClientBuilder};
-use jsonrpsee::rpc_params;
-use p3d::p3d_process;
+use jsonrpsee::core::JsonValue;
 use primitive_types::{H256, U256};
 use sha3::{Digest, Sha3_256};
-use std::collections::HashSet;
 use std::result::Result;
 use std::str::FromStr;
-use std::sync::Mutex;
-use std::thread;
-use std::time::Duration;
-use ansi_term::Style;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
 
 extern crate redis;
 
+use crate::blocks::{Block, BlockStatus};
+use crate::config::Config;
+use crate::error::ProxyError;
 use crate::message::{Message, StatsPayload};
-use crate::utils::log;
-use crate::worker::{
-    AlgoType, DoubleHash, DynamicMiningParams, MiningObj, MiningParams, P3dParams,
-};
-use mongodb::{options::ClientOptions, Client as ClientMongo, Cursor};
-use mongodb::bson::{DateTime, doc};
-use mongodb::options::FindOptions;
+use crate::metrics;
+use crate::node::NodeClient;
+use crate::payout::{PayoutConfig, PayoutMode};
+use crate::state::{ProcessedHash, SharedState};
+use crate::storage::ShareStore;
+use crate::validation::{Priority, ValidationPool};
+use crate::worker::{DoubleHash, MiningObj, MiningParams, P3dParams};
+use mongodb::bson::{DateTime, oid::ObjectId};
 use serde::{Deserialize, Serialize};
 
 pub const BLOCK_TIME_SEC: u64 = 60;
-/// Block time interval in milliseconds.
-pub const BLOCK_TIME: u64 = BLOCK_TIME_SEC * 1000;
 // pub const BLOCK_TIME_WINDOW: u64 = BLOCK_TIME_SEC * 1000;
-pub const TARGET_BLOCK_TIME: u64 = BLOCK_TIME_SEC * 1000;
 
 pub const HOUR_HEIGHT: u64 = 3600 / BLOCK_TIME_SEC;
 // /// A day is 1440 blocks
@@ -45,16 +43,51 @@ pub const HOUR_HEIGHT: u64 = 3600 / BLOCK_TIME_SEC;
 pub const DIFFICULTY_ADJUST_WINDOW: u64 = HOUR_HEIGHT;
 /// Clamp factor to use for difficulty adjustment
 /// Limit value to within this factor of goal
-pub const CLAMP_FACTOR: u128 = 2;
+pub const CLAMP_FACTOR: u64 = 2;
 /// Dampening factor to use for difficulty adjustment
-pub const DIFFICULTY_DAMP_FACTOR: u128 = 3;
+pub const DIFFICULTY_DAMP_FACTOR: u64 = 3;
 /// Minimum difficulty, enforced in diff retargetting
 /// avoids getting stuck when trying to increase difficulty subject to dampening
 const INITIAL_DIFFICULTY: u64 = 2000000;
-pub const MIN_DIFFICULTY: u128 = INITIAL_DIFFICULTY as u128;
+pub const MIN_DIFFICULTY: u64 = INITIAL_DIFFICULTY;
 /// Maximum difficulty.
 pub const MAX_DIFFICULTY: u128 = u128::max_value();
 
+/// Shares a rig must have found before its difficulty is adjusted,
+/// so the adjust window can not be smaller
+pub const VARDIFF_MIN_SHARES: u64 = 5;
+
+/// Rigs that asked for work this recently are counted as connected
+pub const RIG_ACTIVE_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// Vardiff settings, the constants above are the defaults
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct DifficultyConfig {
+    /// Block time of the chain, also the time a rig should take to find a share
+    pub(crate) block_time_sec: u64,
+    /// Number of shares used to calculate difficulty adjustments
+    pub(crate) adjust_window: u64,
+    pub(crate) clamp_factor: u64,
+    pub(crate) damp_factor: u64,
+    /// Difficulty of a rig until it has enough shares
+    pub(crate) initial_difficulty: u64,
+    pub(crate) min_difficulty: u64,
+}
+
+impl Default for DifficultyConfig {
+    fn default() -> Self {
+        DifficultyConfig {
+            block_time_sec: BLOCK_TIME_SEC,
+            adjust_window: DIFFICULTY_ADJUST_WINDOW,
+            clamp_factor: CLAMP_FACTOR,
+            damp_factor: DIFFICULTY_DAMP_FACTOR,
+            initial_difficulty: INITIAL_DIFFICULTY,
+            min_difficulty: MIN_DIFFICULTY,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct DifficultyAndTimestamp {
     pub difficulty: U256,
@@ -84,60 +117,138 @@ pub fn get_hash_difficulty(hash: &H256) -> U256 {
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Share {
+    #[serde(rename = "_id", default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
     pub miner_wallet: String,
     pub rig_name: String,
     pub timestamp: DateTime,
     pub difficulty: U256,
+    /// Rewarded by a confirmed block (PPLNS) or credited when submitted (PPS)
     pub accounted: bool,
     pub paid: bool,
+    /// The share also met the network difficulty and was pushed to the node
+    #[serde(default)]
+    pub block_candidate: bool,
+}
+
+/// Outcome of an accepted object, rejected ones are returned as a ProxyError
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShareStatus {
+    /// Meets the pool difficulty and is recorded as a share
+    Accepted,
+    /// Also meets the network difficulty
+    BlockCandidate,
+}
+
+/// Result returned to the miner for every accepted object
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareResult {
+    pub status: ShareStatus,
+    pub difficulty: U256,
+    pub pow_difficulty: U256,
+    pub win_difficulty: U256,
 }
 
 pub struct AppContex {
-    pub(crate) p3d_params: P3dParams,
     pub(crate) pool_id: String,
+    /// Tells this proxy instance apart from the others sharing the pool state
+    pub(crate) instance_id: String,
     pub(crate) proxy_address: String,
-    pub(crate) cur_state: Mutex<Option<MiningParams>>,
-    pub(crate) dynamic_mp: Mutex<Option<DynamicMiningParams>>,
-    pub(crate) processed_hashes: Mutex<Option<HashSet<H256>>>,
-
-    pub(crate) mongo: ClientMongo,
-    pub(crate) client: HttpClient,
+    /// Current work, jobs, processed hashes and vardiff
+    pub(crate) state: Arc<dyn SharedState>,
+    /// Published by the chain head watcher every time the work changes
+    pub(crate) new_work: broadcast::Sender<MiningParams>,
+    /// Published with (wallet, rig_name) every time a rig's vardiff moves
+    pub(crate) difficulty_changed: broadcast::Sender<(String, String)>,
+    pub(crate) payout: PayoutConfig,
+    pub(crate) difficulty: DifficultyConfig,
+    /// How often the chain head watcher asks the node for work
+    pub(crate) work_poll_interval: Duration,
+    /// Last time every (wallet, rig_name) was served work
+    pub(crate) rigs_seen: Mutex<HashMap<(String, String), Instant>>,
+    /// Runs p3d_process away from the async runtime
+    pub(crate) validation: ValidationPool,
+
+    pub(crate) store: Arc<dyn ShareStore>,
+    pub(crate) node: Arc<dyn NodeClient>,
 }
 
 impl AppContex {
     pub(crate) async fn new(
-        p3d_params: P3dParams,
-        node_addr: &str,
-        proxy_address: String,
-        pool_id: String,
-        mongo_addr: &str,
+        config: &Config,
+        node: Arc<dyn NodeClient>,
+        store: Arc<dyn ShareStore>,
+        state: Arc<dyn SharedState>,
     ) -> anyhow::Result<Self> {
-
-        let client_options = ClientOptions::parse(mongo_addr)
-            .await
-            .expect("Failed to load mongoDB.");
+        let workers = config
+            .validation
+            .workers
+            .unwrap_or_else(|| std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
 
         Ok(AppContex {
-            p3d_params,
-            pool_id,
-            proxy_address,
-            cur_state: Mutex::new(None),
-            dynamic_mp: Mutex::new(None),
-            processed_hashes: Mutex::new(Some(HashSet::new())),
-            mongo: ClientMongo::with_options(client_options)?,
-            client: HttpClientBuilder::default().build(node_addr)?,
+            validation: ValidationPool::new(
+                P3dParams::new(config.proxy.algo.as_str()),
+                workers,
+                config.validation.queue_size,
+            )?,
+            pool_id: config.proxy.pool_id.clone().unwrap_or_default(),
+            instance_id: Uuid::new_v4().simple().to_string(),
+            proxy_address: config.proxy.proxy_address.clone(),
+            state,
+            new_work: broadcast::channel(16).0,
+            difficulty_changed: broadcast::channel(1024).0,
+            payout: config.payout.clone(),
+            difficulty: config.difficulty.clone(),
+            work_poll_interval: Duration::from_millis(config.proxy.work_poll_interval_ms),
+            rigs_seen: Mutex::new(HashMap::new()),
+            store,
+            node,
         })
     }
 
-    pub(crate) async fn get_mining_params(&self) -> Result<String, Error> {
-        let meta: JsonValue = self
-            .client
-            .request::<JsonValue, _>(
-                "poscan_getMiningParams",
-                rpc_params![serde_json::json!(self.pool_id)],
-            )
-            .await
-            .unwrap();
+    /// Serves the rig from the work cached by the chain head watcher
+    pub(crate) async fn get_mining_params(&self, wallet: String, rig_name: String) -> Result<String, ProxyError> {
+        let mining_params = self.current_work().await?.ok_or(ProxyError::NoWork)?;
+        let (_job_id, encoded) = self.encode_mining_params(&mining_params, &wallet, &rig_name).await?;
+        Ok(encoded)
+    }
+
+    pub(crate) async fn current_work(&self) -> Result<Option<MiningParams>, ProxyError> {
+        self.state.get_work().await.map_err(ProxyError::storage)
+    }
+
+    /// Polls the node and publishes new work whenever the chain head changes
+    pub(crate) async fn watch_chain_head(self: Arc<Self>) {
+        let mut last_pre_hash = None;
+        loop {
+            match self.fetch_mining_params().await {
+                Ok(mining_params) if last_pre_hash != Some(mining_params.pre_hash) => {
+                    match self.state.set_work(mining_params.clone()).await {
+                        Ok(()) => {
+                            last_pre_hash = Some(mining_params.pre_hash);
+                            info!(
+                                pre_hash = ?mining_params.pre_hash,
+                                win_difficulty = %mining_params.win_difficulty,
+                                pow_difficulty = %mining_params.pow_difficulty,
+                                "🔗 New work"
+                            );
+                            metrics::work(&mining_params);
+                            let _ = self.new_work.send(mining_params);
+                        }
+                        Err(e) => error!(error = %e, "🚩 New work could not be stored"),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => warn!(error = %e, "🚩 Mining params could not be fetched"),
+            }
+            tokio::time::sleep(self.work_poll_interval).await;
+        }
+    }
+
+    /// Asks the node for the network mining params
+    async fn fetch_mining_params(&self) -> Result<MiningParams, ProxyError> {
+        let meta: JsonValue = self.node.get_mining_params(&self.pool_id).await?;
 
         let default_response: Vec<JsonValue> = Vec::new();
 
@@ -148,18 +259,21 @@ impl AppContex {
             .filter_map(|param| param.as_str().map(String::from))
             .collect();
 
-        let (pre_hash, parent_hash, win_difficulty, mut pow_difficulty, pub_key) =
+        fn invalid<E>(_: E) -> ProxyError {
+            ProxyError::node("Invalid mining params received from the node")
+        }
+        let (pre_hash, parent_hash, win_difficulty, pow_difficulty, pub_key) =
             match content.as_slice() {
                 [pre_hash, parent_hash, win_difficulty, pow_difficulty, pub_key] => (
-                    H256::from_str(pre_hash).unwrap(),
-                    H256::from_str(parent_hash).unwrap(),
-                    U256::from_str_radix(win_difficulty, 16).unwrap(),
-                    U256::from_str_radix(pow_difficulty, 16).unwrap(),
-                    U256::from_str_radix(pub_key, 16).unwrap(),
+                    H256::from_str(pre_hash).map_err(invalid)?,
+                    H256::from_str(parent_hash).map_err(invalid)?,
+                    U256::from_str_radix(win_difficulty, 16).map_err(invalid)?,
+                    U256::from_str_radix(pow_difficulty, 16).map_err(invalid)?,
+                    U256::from_str_radix(pub_key, 16).map_err(invalid)?,
                 ),
                 _ => {
-                    return Err(Error::Custom(
-                        "There are not enough elements in content".into(),
+                    return Err(ProxyError::node(
+                        "There are not enough elements in content",
                     ));
                 }
             };
@@ -167,25 +281,88 @@ impl AppContex {
         // Reverse the bytes of the public key
         let mut pub_key_extra = pub_key.clone().encode();
         pub_key_extra.reverse();
-        let pub_key_extra = ecies_ed25519::PublicKey::from_bytes(&pub_key_extra).unwrap();
+        let pub_key_extra = ecies_ed25519::PublicKey::from_bytes(&pub_key_extra).map_err(invalid)?;
 
-        let dynamic_diff: DynamicMiningParams = {
-            let dyn_param = self.dynamic_mp.lock().unwrap();
-            if let Some(dp) = (*dyn_param).clone() {
-                dp
-            } else {
-                drop(dyn_param);
-                DynamicMiningParams {
-                    dynamic_difficulty: U256::zero(),
-                    no_shares_round: false,
-                }
-            }
+        Ok(MiningParams {
+            pre_hash,
+            parent_hash,
+            win_difficulty,
+            pow_difficulty,
+            pub_key: pub_key_extra,
+        })
+    }
+
+    /// Encodes the mining params with the rig's own pool difficulty and the job id they were issued as
+    pub(crate) async fn encode_mining_params(
+        &self,
+        mining_params: &MiningParams,
+        wallet: &str,
+        rig_name: &str,
+    ) -> Result<(u64, String), ProxyError> {
+        self.rig_seen(wallet, rig_name);
+
+        let pow_difficulty = self.rig_difficulty(wallet, rig_name, mining_params).await?;
+        let rig_params = MiningParams {
+            pow_difficulty,
+            ..mining_params.clone()
         };
+        let job_id = self
+            .state
+            .issue_job(rig_params.clone())
+            .await
+            .map_err(ProxyError::storage)?;
+
+        let MiningParams {
+            pre_hash,
+            parent_hash,
+            win_difficulty,
+            pub_key,
+            ..
+        } = rig_params;
+        let pub_key = U256::from_big_endian(&pub_key.to_bytes());
+
+        let encoded = hex::encode(
+            (
+                pre_hash,
+                parent_hash,
+                win_difficulty,
+                pow_difficulty,
+                pub_key,
+                job_id
+            )
+                .encode()
+        );
+
+        Ok((job_id, encoded))
+    }
+
+    /// Keeps the connected rigs gauge up to date
+    fn rig_seen(&self, wallet: &str, rig_name: &str) {
+        let mut rigs_seen = self.rigs_seen.lock().unwrap();
+        rigs_seen.insert((wallet.to_string(), rig_name.to_string()), Instant::now());
+        rigs_seen.retain(|_, seen_at| seen_at.elapsed() < RIG_ACTIVE_WINDOW);
+        metrics::connected_rigs(rigs_seen.len());
+    }
 
-        let DynamicMiningParams {
-            dynamic_difficulty,
+    /// Pool difficulty for a rig, taken from its vardiff state
+    pub(crate) async fn rig_difficulty(
+        &self,
+        wallet: &str,
+        rig_name: &str,
+        mining_params: &MiningParams,
+    ) -> Result<U256, ProxyError> {
+        let dynamic_difficulty = self
+            .state
+            .get_rig_difficulty(wallet, rig_name)
+            .await
+            .map_err(ProxyError::storage)?
+            .unwrap_or_default();
+
+        let MiningParams {
+            win_difficulty,
+            mut pow_difficulty,
             ..
-        } = dynamic_diff;
+        } = mining_params.clone();
 
         if dynamic_difficulty > pow_difficulty {
             pow_difficulty = dynamic_difficulty;
@@ -197,175 +374,245 @@ impl AppContex {
             }
         }
 
-        let mut lock = self.cur_state.lock().unwrap();
-        (*lock) = Some(MiningParams {
+        Ok(pow_difficulty)
+    }
+
+    pub(crate) async fn push_to_pool(&self, hash: String, obj: String, wallet: String, rig_name: String, job_id: u64) -> Result<ShareResult, ProxyError> {
+        let result = self.process_object(hash, obj, wallet, rig_name, job_id).await;
+        metrics::share_result(&result);
+        result
+    }
+
+    async fn process_object(&self, hash: String, obj: String, wallet: String, rig_name: String, job_id: u64) -> Result<ShareResult, ProxyError> {
+        let hash = H256::from_str(&hash).map_err(|_| ProxyError::BadHash(format!("Invalid hash {}", hash)))?;
+
+        let job = self.state.get_job(job_id).await.map_err(ProxyError::storage)?;
+        let MiningParams {
             pre_hash,
             parent_hash,
             win_difficulty,
             pow_difficulty,
-            pub_key: pub_key_extra,
-        });
-
-        Ok(format!(
-            "{}",
-            hex::encode(
-                (
-                    pre_hash,
-                    parent_hash,
-                    win_difficulty,
-                    pow_difficulty,
-                    pub_key
-                )
-                    .encode()
-            )
-        ))
-    }
-
-    pub(crate) async fn push_to_pool(&self, _hash: String, obj: String, wallet: String, rig_name: String) -> Result<String, Error> {
-        let P3dParams { algo, sect, grid } = self.p3d_params.clone();
-        let _hash = H256::from_str(&_hash).unwrap();
+            ..
+        } = match job {
+            Some(mp) => mp,
+            None => {
+                info!(%wallet, rig = %rig_name, job_id, "🚩 Unknown job");
+                return Err(ProxyError::UnknownJob(job_id));
+            }
+        };
 
-        loop {
-            let mining_params = {
-                let params_lock = self.cur_state.lock().unwrap();
-                if let Some(mp) = (*params_lock).clone() {
-                    mp
-                } else {
-                    drop(params_lock);
-                    thread::sleep(Duration::from_millis(10));
-                    continue;
-                }
-            };
+        self.check_stale(&wallet, &rig_name, job_id, pre_hash).await?;
 
-            let MiningParams {
-                pre_hash,
-                parent_hash,
-                win_difficulty,
-                pow_difficulty,
-                ..
-            } = mining_params;
-            let rot_hash = match &algo {
-                AlgoType::Grid2dV3_1 => pre_hash,
-                _ => parent_hash,
-            };
-
-            let rot = rot_hash.encode()[0..4].try_into().ok();
+        let mining_obj: MiningObj = MiningObj {
+            obj_id: 1,
+            obj: obj.as_bytes().to_vec(),
+        };
 
-            let mining_obj: MiningObj = MiningObj {
-                obj_id: 1,
-                obj: obj.as_bytes().to_vec(),
-            };
+        // The claimed hash is enough to tell a likely block candidate, so it
+        // goes ahead of the shares waiting to be validated
+        let claimed_work = Compute {
+            difficulty: win_difficulty,
+            pre_hash,
+            poscan_hash: DoubleHash { pre_hash, obj_hash: hash }.calc_hash(),
+        }
+        .get_work();
+        let priority = if get_hash_difficulty(&claimed_work) >= win_difficulty {
+            Priority::High
+        } else {
+            Priority::Normal
+        };
 
-            let res_hashes = p3d_process(
-                mining_obj.obj.as_slice(),
-                algo.as_p3d_algo(),
-                grid as i16,
-                sect as i16,
-                rot,
+        let obj_hash = self
+            .validation
+            .obj_hash(priority, pre_hash, parent_hash, mining_obj.obj)
+            .await?
+            .ok_or(ProxyError::InvalidObject)?;
+
+        // The miner claims the object hashes to `hash`, a buggy or malicious
+        // miner is caught here before the share goes any further
+        if obj_hash != hash {
+            warn!(
+                %wallet,
+                rig = %rig_name,
+                claimed = ?hash,
+                computed = ?obj_hash,
+                "🚩 Hash mismatch"
             );
+            return Err(ProxyError::HashMismatch {
+                claimed: hash,
+                computed: obj_hash,
+            });
+        }
 
-            let (_first_hash, _obj_hash, poscan_hash) = match res_hashes {
-                Ok(hashes) if !hashes.is_empty() => {
-                    let first_hash = hashes[0].clone();
-                    let obj_hash = H256::from_str(&first_hash).unwrap();
-
-                    let mut processed_hashes = {
-                        let params_lock = self.processed_hashes.lock().unwrap();
-                        if let Some(mp) = (*params_lock).clone() {
-                            mp
-                        } else {
-                            drop(params_lock);
-                            thread::sleep(Duration::from_millis(10));
-                            continue;
-                        }
-                    };
-
-                    if processed_hashes.contains(&obj_hash) {
-                        log(format!(
-                            "🚩 Duplicated hash discarded {:x}",
-                            obj_hash.clone()
-                        ));
-                        break;
-                    }
+        // New work may have arrived while the object was waiting to be validated
+        self.check_stale(&wallet, &rig_name, job_id, pre_hash).await?;
 
-                    let poscan_hash = DoubleHash { pre_hash, obj_hash }.calc_hash();
-                    processed_hashes.insert(obj_hash.clone());
+        // Checked and recorded in one atomic step, two concurrent
+        // submissions of the same object cannot both get through
+        let processed = self
+            .state
+            .insert_processed_hash(pre_hash, obj_hash)
+            .await
+            .map_err(ProxyError::storage)?;
+        match processed {
+            ProcessedHash::Inserted => {}
+            ProcessedHash::Duplicate => {
+                warn!(%wallet, rig = %rig_name, ?obj_hash, ?pre_hash, "🚩 Duplicated hash discarded");
+                return Err(ProxyError::Duplicate(obj_hash));
+            }
+            ProcessedHash::UnknownHead => {
+                info!(%wallet, rig = %rig_name, job_id, ?pre_hash, "🚩 Stale job");
+                return Err(ProxyError::StaleJob(job_id));
+            }
+        }
 
-                    let mut lock_ph = self.processed_hashes.lock().unwrap();
-                    (*lock_ph) = Some(processed_hashes);
-                    thread::sleep(Duration::from_millis(10));
+        let poscan_hash = DoubleHash { pre_hash, obj_hash }.calc_hash();
 
-                    (first_hash, obj_hash, poscan_hash)
-                }
-                _ => {
-                    continue;
-                }
-            };
+        // The work depends on the difficulty it is checked against, so the
+        // share and the block candidate are validated separately
+        let diff = get_hash_difficulty(
+            &Compute {
+                difficulty: pow_difficulty,
+                pre_hash,
+                poscan_hash,
+            }
+            .get_work(),
+        );
+
+        if diff < pow_difficulty {
+            info!(
+                %wallet,
+                rig = %rig_name,
+                difficulty = %diff,
+                %pow_difficulty,
+                "🚩 Low difficulty share rejected"
+            );
+            return Err(ProxyError::LowDifficulty {
+                difficulty: diff,
+                pow_difficulty,
+                win_difficulty,
+            });
+        }
 
-            for difficulty in [pow_difficulty, win_difficulty] {
-                let comp = Compute {
-                    difficulty,
-                    pre_hash,
-                    poscan_hash,
-                };
+        let win_diff = get_hash_difficulty(
+            &Compute {
+                difficulty: win_difficulty,
+                pre_hash,
+                poscan_hash,
+            }
+            .get_work(),
+        );
+        let status = if win_diff >= win_difficulty {
+            ShareStatus::BlockCandidate
+        } else {
+            ShareStatus::Accepted
+        };
 
-                let diff = get_hash_difficulty(&comp.get_work());
-
-                self.submit_share(
-                    "pool-p3d",
-                    "shares",
-                    wallet.clone(),
-                    rig_name.clone(),
-                    diff,
-                ).await.unwrap();
-
-                let response = self
-                    .client
-                    .request::<u64, _>(
-                        "poscan_pushMiningObject",
-                        rpc_params![serde_json::json!(1), serde_json::json!(obj)],
-                    )
-                    .await
-                    .unwrap();
-
-                if response == 0 {
-                    log(format!(
-                        "💎 Share found difficulty: {} :: Pool Difficulty: {} :: Chain difficulty: {}",
-                        Style::new().bold().paint(format!("{:.2}", &diff)),
-                        Style::new().bold().paint(format!("{:.2}", &pow_difficulty)),
-                        &win_difficulty
-                    ));
-                    self.adjust_difficulty(wallet, rig_name).await.unwrap();
+        let block_candidate = status == ShareStatus::BlockCandidate;
+        let pps = self.payout.mode == PayoutMode::Pps;
+
+        info!(
+            %wallet,
+            rig = %rig_name,
+            difficulty = %diff,
+            %pow_difficulty,
+            %win_difficulty,
+            "💎 Share found"
+        );
+
+        // Only block candidates are worth the node's time. They are pushed
+        // before anything is stored so a database failure cannot lose a block
+        let mut block_accepted = false;
+        if block_candidate {
+            info!(
+                %wallet,
+                rig = %rig_name,
+                ?pre_hash,
+                difficulty = %win_diff,
+                %win_difficulty,
+                "🏆 Block candidate"
+            );
+
+            let response = self.node.push_mining_object(&obj).await;
+            metrics::block_candidate(matches!(response, Ok(0)));
+
+            if let Ok(0) = response {
+                block_accepted = true;
+                let stored = self.store.insert_block(
+                    Block {
+                        pre_hash,
+                        parent_hash,
+                        poscan_hash,
+                        miner_wallet: wallet.clone(),
+                        rig_name: rig_name.clone(),
+                        difficulty: win_diff,
+                        timestamp: DateTime::now(),
+                        status: BlockStatus::Pending,
+                        block_number: None,
+                        block_hash: None,
+                    },
+                ).await;
+                if let Err(e) = stored {
+                    error!(?pre_hash, error = %e, "🚩 Block could not be stored");
                 }
-                break;
+            } else {
+                warn!(?pre_hash, ?response, "🚩 Block candidate not accepted by the nodes");
             }
-            break;
         }
-        Ok(String::from("Pushed to pool for validation"))
-    }
 
-    async fn submit_share(
-        &self,
-        db_name: &str,
-        coll_name: &str,
-        miner_wallet: String,
-        rig_name: String,
-        difficulty: U256,
-    ) -> anyhow::Result<u64> {
-        let db = self.mongo.database(db_name);
-        let coll = db.collection::<Share>(coll_name);
+        // Shares are credited at the difficulty they were accepted at
+        let credit = if pps {
+            self.pps_credit(wallet.clone(), pre_hash, poscan_hash, pow_difficulty, win_difficulty)
+        } else {
+            None
+        };
         let share = Share {
-            miner_wallet,
-            rig_name,
+            id: None,
+            miner_wallet: wallet.clone(),
+            rig_name: rig_name.clone(),
             timestamp: DateTime::now(),
-            difficulty,
-            accounted: false,
+            difficulty: pow_difficulty,
+            accounted: credit.is_some(),
             paid: false,
+            block_candidate,
+        };
+        let stored = match credit {
+            Some(credit) => self.store.insert_credited_share(share, credit).await,
+            None => self.store.insert_share(share).await,
         };
-        coll.insert_one(share, None).await?;
-        Ok(0)
+        match stored {
+            Ok(()) => {}
+            // The block is already on its way to the chain, the submit did not fail
+            Err(e) if block_accepted => {
+                error!(%wallet, rig = %rig_name, ?pre_hash, error = %e, "🚩 Share of an accepted block could not be stored")
+            }
+            Err(e) => return Err(ProxyError::storage(e)),
+        }
+
+        if let Err(e) = self.adjust_difficulty(wallet.clone(), rig_name.clone()).await {
+            error!(%wallet, rig = %rig_name, error = %e, "🚩 Difficulty could not be adjusted");
+        }
+
+        Ok(ShareResult {
+            status,
+            difficulty: diff,
+            pow_difficulty,
+            win_difficulty,
+        })
     }
 
+    /// Rejects jobs issued for a chain head other than the current one
+    async fn check_stale(&self, wallet: &str, rig_name: &str, job_id: u64, pre_hash: H256) -> Result<(), ProxyError> {
+        let cur_pre_hash = self.current_work().await?.map(|mp| mp.pre_hash);
+        if cur_pre_hash != Some(pre_hash) {
+            info!(%wallet, rig = %rig_name, job_id, ?pre_hash, "🚩 Stale job");
+            return Err(ProxyError::StaleJob(job_id));
+        }
+        Ok(())
+    }
+
+    /// Stores the stats reported by a rig and returns their id. Older miners
+    /// send no wallet, their reports are acknowledged but not stored.
     pub(crate) async fn push_stats(
         &self,
         name: String,
@@ -373,53 +620,25 @@ impl AppContex {
         tag: String,
         hashrate: String,
         good_hashrate: String,
-    ) -> Result<String, Error> {
-        let _payload = StatsPayload {
+        wallet: Option<String>,
+    ) -> Result<String, ProxyError> {
+        let payload = StatsPayload {
             name,
             cores,
             tag,
             hashrate,
-            good_hashrate: good_hashrate.clone(),
+            good_hashrate,
         };
 
-        // self.adjust_difficulty(good_hashrate.clone());
-
-        // let message = Message::new(self.member_id.clone(), payload);
-        // let response = self
-        //     .store_stats(message)
-        //     .map_err(|e| e.to_string())
-        //     .unwrap();
-        // Ok(hex::encode(payload.encode()))
-        Ok(String::from("WIP"))
-    }
-
-    fn store_stats(&self, _message: Message) -> Result<String, Error> {
-        Ok(String::from("store_stats"))
-    }
-
-    async fn get_miner_shares(
-        &self,
-        db_name: &str,
-        coll_name: &str,
-        miner_wallet: String,
-        rig_name: String,
-    ) -> anyhow::Result<Vec<Share>> {
-        let db = self.mongo.database(db_name);
-        let coll = db.collection::<Share>(coll_name);
-        let filter = doc! {"miner_wallet": miner_wallet, "rig_name": rig_name ,"accounted": false};
-        let find_options = FindOptions::builder()
-            .sort(doc! { "timestamp": -1 })
-            .build();
-        let mut cursor: Cursor<Share> = coll.find(filter, find_options).await?;
-
-        let mut result = Vec::new();
-
-        while cursor.advance().await? {
-            let share = cursor.deserialize_current()?;
-            result.push(share);
-        }
-
-        Ok(result)
+        let Some(wallet) = wallet else {
+            debug!(rig = %payload.name, "Stats reported without a wallet, not stored");
+            return Ok(String::new());
+        };
+        let message = Message::new(wallet, payload);
+        self.store
+            .store_stats(message)
+            .await
+            .map_err(ProxyError::storage)
     }
 
     pub async fn adjust_difficulty(
@@ -427,19 +646,29 @@ impl AppContex {
         wallet: String,
         rig_name: String,
     ) -> anyhow::Result<()> {
-        log(String::from("💯 Adjusting difficulty"));
-        let db_name = "pool-p3d";
-        let coll_name = "shares";
-
+        debug!(%wallet, rig = %rig_name, "💯 Adjusting difficulty");
+
+        let DifficultyConfig {
+            block_time_sec,
+            adjust_window,
+            clamp_factor,
+            damp_factor,
+            initial_difficulty,
+            min_difficulty,
+        } = self.difficulty;
+        // Block time interval in milliseconds
+        let block_time = block_time_sec * 1000;
+
+        // Only the most recent shares fit in the adjustment window
         let mut shares = self
-            .get_miner_shares(db_name, coll_name, wallet.clone(), rig_name.clone())
-            .await
-            .unwrap();
+            .store
+            .get_rig_shares(&wallet, &rig_name, adjust_window + 1)
+            .await?;
 
-        if shares.len() > 5 {
+        if shares.len() as u64 > VARDIFF_MIN_SHARES {
             shares.sort_by_key(|share| share.timestamp);
 
-            let mut data = vec![None; DIFFICULTY_ADJUST_WINDOW as usize];
+            let mut data = vec![None; adjust_window as usize];
             for i in 1..shares.len() {
                 data[i - 1] = Some(DifficultyAndTimestamp {
                     timestamp: shares[i].timestamp.timestamp_millis(),
@@ -448,13 +677,13 @@ impl AppContex {
             }
 
             let mut ts_delta = 0;
-            for i in 1..DIFFICULTY_ADJUST_WINDOW as usize {
+            for i in 1..adjust_window as usize {
                 let prev = data[i - 1].as_ref().map(|d| d.timestamp);
                 let cur = data[i].as_ref().map(|d| d.timestamp);
 
                 let delta = match (prev, cur) {
                     (Some(prev), Some(cur)) => cur.saturating_sub(prev),
-                    _ => TARGET_BLOCK_TIME as i64,
+                    _ => block_time as i64,
                 };
                 ts_delta += delta;
             }
@@ -464,54 +693,58 @@ impl AppContex {
             }
 
             let mut diff_sum = U256::zero();
-            for i in 0..DIFFICULTY_ADJUST_WINDOW as usize {
+            for i in 0..adjust_window as usize {
                 let diff = match data[i].as_ref().map(|d| d.difficulty) {
                     Some(diff) => U256::from(diff),
-                    None => U256::from(INITIAL_DIFFICULTY),
+                    None => U256::from(initial_difficulty),
                 };
                 diff_sum += diff;
             }
 
-            if diff_sum < U256::from(MIN_DIFFICULTY) {
-                diff_sum = U256::from(MIN_DIFFICULTY);
+            if diff_sum < U256::from(min_difficulty) {
+                diff_sum = U256::from(min_difficulty);
             }
 
             let adj_ts = self.clamp(
                 self.damp(
                     ts_delta as u128,
-                    BLOCK_TIME as u128,
-                    DIFFICULTY_DAMP_FACTOR,
+                    block_time as u128,
+                    damp_factor as u128,
                 ),
-                BLOCK_TIME as u128,
-                CLAMP_FACTOR,
+                block_time as u128,
+                clamp_factor as u128,
             );
 
             let difficulty = min(
                 U256::from(MAX_DIFFICULTY),
                 max(
-                    U256::from(MIN_DIFFICULTY),
-                    diff_sum * U256::from(TARGET_BLOCK_TIME) / U256::from(adj_ts),
+                    U256::from(min_difficulty),
+                    diff_sum * U256::from(block_time) / U256::from(adj_ts),
                 ),
             );
 
-            let mut lock_mp = self.dynamic_mp.lock().unwrap();
-            (*lock_mp) = Some(DynamicMiningParams {
-                dynamic_difficulty: difficulty,
-                no_shares_round: false,
-            });
-            log(format!("🦾 New adjusted difficulty set to {}", difficulty));
+            self.set_rig_difficulty(&wallet, &rig_name, difficulty).await?;
+            debug!(%wallet, rig = %rig_name, %difficulty, "🦾 New adjusted difficulty");
         } else {
-            let mut lock_mp = self.dynamic_mp.lock().unwrap();
-            (*lock_mp) = Some(DynamicMiningParams {
-                dynamic_difficulty: U256::from(INITIAL_DIFFICULTY),
-                no_shares_round: false,
-            });
-            log(format!("🦾 Difficulty set to {}", INITIAL_DIFFICULTY));
+            self.set_rig_difficulty(&wallet, &rig_name, U256::from(initial_difficulty)).await?;
+            debug!(%wallet, rig = %rig_name, difficulty = initial_difficulty, "🦾 Initial difficulty");
         }
 
         Ok(())
     }
 
+    /// Stores the rig's vardiff and lets its subscribers know when it moved
+    async fn set_rig_difficulty(&self, wallet: &str, rig_name: &str, difficulty: U256) -> anyhow::Result<()> {
+        let previous = self.state.set_rig_difficulty(wallet, rig_name, difficulty).await?;
+
+        if previous != Some(difficulty) {
+            let _ = self
+                .difficulty_changed
+                .send((wallet.to_string(), rig_name.to_string()));
+        }
+        Ok(())
+    }
+
     /// Move value linearly toward a goal
     fn damp(&self, actual: u128, goal: u128, damp_factor: u128) -> u128 {
         (actual + (damp_factor - 1) * goal) / damp_factor
@@ -522,3 +755,122 @@ impl AppContex {
         max(goal / clamp_factor, min(actual, goal * clamp_factor))
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::node::NodePool;
+    use crate::state::LocalState;
+    use crate::storage::MemoryStore;
+
+    /// Pool context on top of an in-memory store and state. Its node is
+    /// never reached unless the chain head watcher is started.
+    pub(crate) async fn context(config: &Config) -> AppContex {
+        let node = NodePool::new(&config.proxy.node_url, config.difficulty.block_time_sec).unwrap();
+        AppContex::new(
+            config,
+            Arc::new(node),
+            Arc::new(MemoryStore::default()),
+            Arc::new(LocalState::default()),
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Share found by the rig `millis_ago` milliseconds ago
+    pub(crate) fn share(wallet: &str, rig_name: &str, millis_ago: i64, difficulty: u64) -> Share {
+        Share {
+            id: None,
+            miner_wallet: wallet.to_string(),
+            rig_name: rig_name.to_string(),
+            timestamp: DateTime::from_millis(DateTime::now().timestamp_millis() - millis_ago),
+            difficulty: U256::from(difficulty),
+            accounted: false,
+            paid: false,
+            block_candidate: false,
+        }
+    }
+
+    async fn adjusted_difficulty(ctx: &AppContex, rig_name: &str, spacing_ms: i64) -> U256 {
+        for i in 0..10 {
+            ctx.store
+                .insert_share(share("wallet", rig_name, (10 - i) * spacing_ms, 2_000_000))
+                .await
+                .unwrap();
+        }
+        ctx.adjust_difficulty(String::from("wallet"), rig_name.to_string()).await.unwrap();
+        ctx.state.get_rig_difficulty("wallet", rig_name).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn initial_difficulty_until_enough_shares() {
+        let ctx = context(&Config::default()).await;
+        for i in 0..5 {
+            ctx.store.insert_share(share("wallet", "rig", i * 1000, 2_000_000)).await.unwrap();
+        }
+        ctx.adjust_difficulty(String::from("wallet"), String::from("rig")).await.unwrap();
+
+        let difficulty = ctx.state.get_rig_difficulty("wallet", "rig").await.unwrap();
+        assert_eq!(difficulty, Some(U256::from(ctx.difficulty.initial_difficulty)));
+    }
+
+    #[tokio::test]
+    async fn faster_rig_gets_higher_difficulty() {
+        let mut config = Config::default();
+        config.difficulty.adjust_window = 5;
+        let ctx = context(&config).await;
+
+        let fast = adjusted_difficulty(&ctx, "fast", 1000).await;
+        let slow = adjusted_difficulty(&ctx, "slow", 600_000).await;
+        assert!(fast > slow);
+        assert!(slow >= U256::from(ctx.difficulty.min_difficulty));
+    }
+
+    #[tokio::test]
+    async fn rig_difficulty_is_capped_at_network_difficulty() {
+        let ctx = context(&Config::default()).await;
+        let mining_params = MiningParams {
+            win_difficulty: U256::from(5_000_000),
+            pow_difficulty: U256::from(1_000),
+            ..Default::default()
+        };
+        assert_eq!(
+            ctx.rig_difficulty("wallet", "rig", &mining_params).await.unwrap(),
+            U256::from(1_000)
+        );
+
+        ctx.state.set_rig_difficulty("wallet", "rig", U256::from(2_000_000)).await.unwrap();
+        assert_eq!(
+            ctx.rig_difficulty("wallet", "rig", &mining_params).await.unwrap(),
+            U256::from(2_000_000)
+        );
+
+        ctx.state.set_rig_difficulty("wallet", "rig", U256::from(9_000_000)).await.unwrap();
+        assert_eq!(
+            ctx.rig_difficulty("wallet", "rig", &mining_params).await.unwrap(),
+            U256::from(5_000_000)
+        );
+    }
+
+    #[tokio::test]
+    async fn stats_are_stored_for_their_wallet() {
+        let ctx = context(&Config::default()).await;
+        let push_stats = |wallet: Option<&str>| {
+            ctx.push_stats(
+                String::from("rig"),
+                String::from("8"),
+                String::from("tag"),
+                String::from("100"),
+                String::from("90"),
+                wallet.map(String::from),
+            )
+        };
+
+        assert_eq!(push_stats(None).await.unwrap(), "");
+        let id = push_stats(Some("wallet")).await.unwrap();
+
+        let reported = ctx.store.get_reported_stats("wallet", DateTime::from_millis(0)).await.unwrap();
+        assert_eq!(reported.len(), 1);
+        assert_eq!(reported[0].id, id);
+    }
+}
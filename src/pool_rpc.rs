@@ -1,19 +1,39 @@
 use std::sync::Arc;
 
-use crate::pool_handler::AppContex;
-use jsonrpsee::core::{async_trait, RpcResult};
+use crate::pool_handler::{AppContex, ShareResult};
+use jsonrpsee::core::{async_trait, RpcResult, SubscriptionResult};
 use jsonrpsee::proc_macros::rpc;
+use jsonrpsee::{PendingSubscriptionSink, SubscriptionMessage};
+use tokio::sync::broadcast::error::RecvError;
 
 #[rpc(server, client)]
 pub trait PoolMiningRpc {
-    /// get_mining_params ask to the blockchain for POOL mining params
+    /// get_mining_params ask to the blockchain for POOL mining params with the rig's difficulty
     #[method(name = "get_mining_params")]
-    async fn get_mining_params(&self) -> RpcResult<String>;
+    async fn get_mining_params(&self, wallet: String, rig_name: String) -> RpcResult<String>;
+
+    /// subscribe_mining_params pushes the rig's params over WebSocket whenever the chain head
+    /// or the rig's difficulty changes
+    #[subscription(
+        name = "subscribe_mining_params" => "mining_params",
+        unsubscribe = "unsubscribe_mining_params",
+        item = String
+    )]
+    async fn subscribe_mining_params(&self, wallet: String, rig_name: String) -> SubscriptionResult;
 
     /// push_to_pool handles the payload from the miner and push it to the POOL
     #[method(name = "push_to_pool")]
-    async fn push_to_pool(&self, hash: String, obj: String, wallet: String, rig_name: String) -> RpcResult<String>;
+    async fn push_to_pool(
+        &self,
+        hash: String,
+        obj: String,
+        wallet: String,
+        rig_name: String,
+        job_id: u64,
+    ) -> RpcResult<ShareResult>;
 
+    /// push_stats stores the stats reported by a miner's rig. The wallet is
+    /// optional for the miners that do not send it yet.
     #[method(name = "push_stats")]
     async fn push_stats(
         &self,
@@ -22,6 +42,7 @@ pub trait PoolMiningRpc {
         tag: String,
         hashrate: String,
         good_hashrate: String,
+        wallet: Option<String>,
     ) -> RpcResult<String>;
 }
 
@@ -37,22 +58,63 @@ impl PoolMiningRpcServerImpl {
 
 #[async_trait]
 impl PoolMiningRpcServer for PoolMiningRpcServerImpl {
-    async fn get_mining_params(&self) -> RpcResult<String> {
+    async fn get_mining_params(&self, wallet: String, rig_name: String) -> RpcResult<String> {
         let response = self
             .ctx
-            .get_mining_params()
-            .await
-            .map_err(|e| e.to_string())
-            .unwrap();
+            .get_mining_params(wallet, rig_name)
+            .await?;
         Ok(response)
     }
-    async fn push_to_pool(&self, hash: String, obj: String, wallet: String, rig_name: String) -> RpcResult<String> {
+    async fn subscribe_mining_params(
+        &self,
+        pending: PendingSubscriptionSink,
+        wallet: String,
+        rig_name: String,
+    ) -> SubscriptionResult {
+        let mut work_rx = self.ctx.new_work.subscribe();
+        let mut difficulty_rx = self.ctx.difficulty_changed.subscribe();
+        let sink = pending.accept().await?;
+
+        let mut mining_params = self.ctx.current_work().await?;
+        loop {
+            if let Some(mp) = &mining_params {
+                let (_job_id, encoded) = self.ctx.encode_mining_params(mp, &wallet, &rig_name).await?;
+                sink.send(SubscriptionMessage::from_json(&encoded)?).await?;
+            }
+
+            // Wait for something that changes this rig's params
+            loop {
+                tokio::select! {
+                    _ = sink.closed() => return Ok(()),
+                    work = work_rx.recv() => match work {
+                        Ok(mp) => {
+                            mining_params = Some(mp);
+                            break;
+                        }
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => return Ok(()),
+                    },
+                    rig = difficulty_rx.recv() => match rig {
+                        Ok((w, r)) if w == wallet && r == rig_name => break,
+                        Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => return Ok(()),
+                    },
+                }
+            }
+        }
+    }
+    async fn push_to_pool(
+        &self,
+        hash: String,
+        obj: String,
+        wallet: String,
+        rig_name: String,
+        job_id: u64,
+    ) -> RpcResult<ShareResult> {
         let response = self
             .ctx
-            .push_to_pool(hash, obj, wallet, rig_name)
-            .await
-            .map_err(|e| e.to_string())
-            .unwrap();
+            .push_to_pool(hash, obj, wallet, rig_name, job_id)
+            .await?;
         Ok(response)
     }
     async fn push_stats(
@@ -62,13 +124,12 @@ impl PoolMiningRpcServer for PoolMiningRpcServerImpl {
         tag: String,
         hashrate: String,
         good_hashrate: String,
+        wallet: Option<String>,
     ) -> RpcResult<String> {
         let response = self
             .ctx
-            .push_stats(name, cores, tag, hashrate, good_hashrate)
-            .await
-            .map_err(|e| e.to_string())
-            .unwrap();
+            .push_stats(name, cores, tag, hashrate, good_hashrate, wallet)
+            .await?;
         Ok(response)
     }    
 }
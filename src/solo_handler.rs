@@ -0,0 +1,136 @@
+use codec::Encode;
+use jsonrpsee::core::JsonValue;
+use primitive_types::{H256, U256};
+use std::result::Result;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+use crate::error::ProxyError;
+use crate::node::NodeClient;
+use crate::pool_handler::{get_hash_difficulty, Compute};
+use crate::worker::{p3d_obj_hash, DoubleHash, P3dParams, SoloMiningParams};
+
+/// Context for SOLO mode: work comes straight from the nodes and found objects
+/// are pushed back to them, without any share bookkeeping.
+pub struct SoloAppContex {
+    pub(crate) p3d_params: P3dParams,
+    pub(crate) proxy_address: String,
+    pub(crate) cur_state: Mutex<Option<SoloMiningParams>>,
+
+    pub(crate) node: Arc<dyn NodeClient>,
+}
+
+impl SoloAppContex {
+    pub(crate) fn new(
+        p3d_params: P3dParams,
+        node: Arc<dyn NodeClient>,
+        proxy_address: String,
+    ) -> Self {
+        SoloAppContex {
+            p3d_params,
+            proxy_address,
+            cur_state: Mutex::new(None),
+            node,
+        }
+    }
+
+    pub(crate) async fn get_meta(&self) -> Result<String, ProxyError> {
+        let meta: JsonValue = self.node.get_meta().await?;
+
+        let pre_hash = meta["pre_hash"].as_str().and_then(|h| H256::from_str(h).ok());
+        let parent_hash = meta["parent_hash"].as_str().and_then(|h| H256::from_str(h).ok());
+        let difficulty = match &meta["difficulty"] {
+            JsonValue::String(d) => U256::from_str_radix(d.trim_start_matches("0x"), 16).ok(),
+            JsonValue::Number(d) => d.as_u64().map(U256::from),
+            _ => None,
+        };
+
+        let (pre_hash, parent_hash, difficulty) = match (pre_hash, parent_hash, difficulty) {
+            (Some(pre_hash), Some(parent_hash), Some(difficulty)) => {
+                (pre_hash, parent_hash, difficulty)
+            }
+            _ => {
+                return Err(ProxyError::node(
+                    "Invalid meta received from the node",
+                ));
+            }
+        };
+
+        let mut lock = self.cur_state.lock().unwrap();
+        (*lock) = Some(SoloMiningParams {
+            pre_hash,
+            parent_hash,
+            difficulty,
+        });
+
+        Ok(hex::encode((pre_hash, parent_hash, difficulty).encode()))
+    }
+
+    pub(crate) async fn push_to_node(&self, hash: String, obj: String) -> Result<String, ProxyError> {
+        let hash = H256::from_str(&hash).map_err(|_| ProxyError::BadHash(format!("Invalid hash {}", hash)))?;
+
+        let mining_params = {
+            let lock = self.cur_state.lock().unwrap();
+            (*lock).clone()
+        };
+        let mining_params = match mining_params {
+            Some(mp) => mp,
+            None => {
+                self.get_meta().await?;
+                self.cur_state.lock().unwrap().clone().unwrap_or_default()
+            }
+        };
+
+        let SoloMiningParams {
+            pre_hash,
+            parent_hash,
+            difficulty,
+        } = mining_params;
+
+        // p3d_process is too heavy for the async runtime, a panic on a malformed
+        // object ends up as an invalid object too
+        let p3d_params = self.p3d_params.clone();
+        let obj_bytes = obj.as_bytes().to_vec();
+        let obj_hash = tokio::task::spawn_blocking(move || p3d_obj_hash(&p3d_params, pre_hash, parent_hash, &obj_bytes))
+            .await
+            .ok()
+            .flatten()
+            .ok_or(ProxyError::InvalidObject)?;
+
+        // Same check as in pool mode, the node is not bothered with an object
+        // that does not hash to what the miner claims
+        if obj_hash != hash {
+            warn!(claimed = ?hash, computed = ?obj_hash, "🚩 Hash mismatch");
+            return Err(ProxyError::HashMismatch {
+                claimed: hash,
+                computed: obj_hash,
+            });
+        }
+
+        let poscan_hash = DoubleHash { pre_hash, obj_hash }.calc_hash();
+        let comp = Compute {
+            difficulty,
+            pre_hash,
+            poscan_hash,
+        };
+        let diff = get_hash_difficulty(&comp.get_work());
+
+        if diff < difficulty {
+            info!(?pre_hash, difficulty = %diff, win_difficulty = %difficulty, "🚩 Low difficulty object rejected");
+            return Err(ProxyError::LowDifficulty {
+                difficulty: diff,
+                pow_difficulty: difficulty,
+                win_difficulty: difficulty,
+            });
+        }
+
+        let response = self.node.push_mining_object(&obj).await?;
+
+        if response == 0 {
+            info!(?pre_hash, difficulty = %diff, win_difficulty = %difficulty, "💎 Block found");
+        }
+
+        Ok(String::from("Pushed to node"))
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use jsonrpsee::core::async_trait;
+use primitive_types::{H256, U256};
+
+use crate::state::{ProcessedHash, SharedState};
+use crate::worker::{DynamicMiningParams, MiningParams};
+
+/// Chain heads whose jobs and object hashes are kept: the current one and the previous one
+pub const KEPT_HEADS: usize = 2;
+
+/// Jobs issued for the last chain heads, each one a MiningParams with the
+/// pool difficulty given to the rig. A rig keeps mining its job until the
+/// next head, however many other jobs were issued meanwhile.
+#[derive(Default)]
+pub(crate) struct Jobs {
+    next_id: u64,
+    /// Oldest first
+    heads: VecDeque<H256>,
+    issued: HashMap<u64, MiningParams>,
+    /// Job id of every (pre_hash, pow_difficulty)
+    ids: HashMap<(H256, U256), u64>,
+}
+
+impl Jobs {
+    /// Forgets the jobs of the heads older than the previous one
+    pub(crate) fn new_head(&mut self, pre_hash: H256) {
+        if self.heads.back() == Some(&pre_hash) {
+            return;
+        }
+        self.heads.push_back(pre_hash);
+        if self.heads.len() > KEPT_HEADS {
+            self.heads.pop_front();
+        }
+
+        let heads = &self.heads;
+        self.issued.retain(|_, mp| heads.contains(&mp.pre_hash));
+        self.ids.retain(|(pre_hash, _), _| heads.contains(pre_hash));
+    }
+
+    /// Returns the id of the job for these params, issuing a new one if needed
+    pub(crate) fn issue(&mut self, mining_params: MiningParams) -> u64 {
+        let key = (mining_params.pre_hash, mining_params.pow_difficulty);
+        if let Some(job_id) = self.ids.get(&key) {
+            return *job_id;
+        }
+
+        self.next_id += 1;
+        self.ids.insert(key, self.next_id);
+        self.issued.insert(self.next_id, mining_params);
+        self.next_id
+    }
+
+    pub(crate) fn get(&self, job_id: u64) -> Option<MiningParams> {
+        self.issued.get(&job_id).cloned()
+    }
+}
+
+/// Number of object hashes remembered for each chain head
+pub const MAX_PROCESSED_HASHES: usize = 100_000;
+
+/// Hashes of the objects submitted for one chain head
+#[derive(Default)]
+struct HeadHashes {
+    pre_hash: H256,
+    seen: HashSet<H256>,
+    order: VecDeque<H256>,
+}
+
+/// Hashes of the objects submitted for the last chain heads. A share checked
+/// before new work arrived still finds the set of the head it was mined on,
+/// objects mined on older heads are stale anyway.
+#[derive(Default)]
+pub(crate) struct ProcessedHashes {
+    /// Oldest first
+    heads: VecDeque<HeadHashes>,
+}
+
+impl ProcessedHashes {
+    /// Starts a set for the new head and forgets the heads older than the previous one
+    pub(crate) fn new_head(&mut self, pre_hash: H256) {
+        if self.heads.back().map(|head| head.pre_hash) == Some(pre_hash) {
+            return;
+        }
+        self.heads.push_back(HeadHashes {
+            pre_hash,
+            ..Default::default()
+        });
+        if self.heads.len() > KEPT_HEADS {
+            self.heads.pop_front();
+        }
+    }
+
+    /// Records the object hash unless it was already submitted for this chain
+    /// head. Heads older than the previous one are not tracked anymore.
+    pub(crate) fn insert(&mut self, pre_hash: H256, obj_hash: H256) -> ProcessedHash {
+        let head = match self.heads.iter_mut().find(|head| head.pre_hash == pre_hash) {
+            Some(head) => head,
+            None => return ProcessedHash::UnknownHead,
+        };
+        if !head.seen.insert(obj_hash) {
+            return ProcessedHash::Duplicate;
+        }
+
+        head.order.push_back(obj_hash);
+        if head.order.len() > MAX_PROCESSED_HASHES {
+            if let Some(oldest) = head.order.pop_front() {
+                head.seen.remove(&oldest);
+            }
+        }
+        ProcessedHash::Inserted
+    }
+}
+
+/// State kept in this process only, for a single proxy instance
+#[derive(Default)]
+pub(crate) struct LocalState {
+    cur_state: Mutex<Option<MiningParams>>,
+    jobs: Mutex<Jobs>,
+    processed_hashes: Mutex<ProcessedHashes>,
+    /// Vardiff state keyed by (wallet, rig_name)
+    dynamic_mp: Mutex<HashMap<(String, String), DynamicMiningParams>>,
+}
+
+#[async_trait]
+impl SharedState for LocalState {
+    async fn get_work(&self) -> anyhow::Result<Option<MiningParams>> {
+        Ok(self.cur_state.lock().unwrap().clone())
+    }
+
+    async fn set_work(&self, mining_params: MiningParams) -> anyhow::Result<()> {
+        self.jobs.lock().unwrap().new_head(mining_params.pre_hash);
+        self.processed_hashes.lock().unwrap().new_head(mining_params.pre_hash);
+        (*self.cur_state.lock().unwrap()) = Some(mining_params);
+        Ok(())
+    }
+
+    async fn issue_job(&self, mining_params: MiningParams) -> anyhow::Result<u64> {
+        Ok(self.jobs.lock().unwrap().issue(mining_params))
+    }
+
+    async fn get_job(&self, job_id: u64) -> anyhow::Result<Option<MiningParams>> {
+        Ok(self.jobs.lock().unwrap().get(job_id))
+    }
+
+    async fn insert_processed_hash(&self, pre_hash: H256, obj_hash: H256) -> anyhow::Result<ProcessedHash> {
+        Ok(self.processed_hashes.lock().unwrap().insert(pre_hash, obj_hash))
+    }
+
+    async fn get_rig_difficulty(&self, wallet: &str, rig_name: &str) -> anyhow::Result<Option<U256>> {
+        Ok(self
+            .dynamic_mp
+            .lock()
+            .unwrap()
+            .get(&(wallet.to_string(), rig_name.to_string()))
+            .map(|dp| dp.dynamic_difficulty))
+    }
+
+    async fn set_rig_difficulty(&self, wallet: &str, rig_name: &str, difficulty: U256) -> anyhow::Result<Option<U256>> {
+        let previous = self.dynamic_mp.lock().unwrap().insert(
+            (wallet.to_string(), rig_name.to_string()),
+            DynamicMiningParams {
+                dynamic_difficulty: difficulty,
+                no_shares_round: false,
+            },
+        );
+        Ok(previous.map(|dp| dp.dynamic_difficulty))
+    }
+
+    /// A single instance holds every lease
+    async fn acquire_lease(&self, _name: &str, _holder: &str, _ttl: Duration) -> anyhow::Result<bool> {
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pre_hash: u8, pow_difficulty: u64) -> MiningParams {
+        MiningParams {
+            pre_hash: H256::repeat_byte(pre_hash),
+            pow_difficulty: U256::from(pow_difficulty),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn jobs_are_issued_once_per_difficulty() {
+        let mut jobs = Jobs::default();
+        jobs.new_head(H256::repeat_byte(1));
+
+        let job_id = jobs.issue(params(1, 10));
+        assert_eq!(jobs.issue(params(1, 10)), job_id);
+        let other = jobs.issue(params(1, 20));
+        assert_ne!(other, job_id);
+        assert_eq!(jobs.get(other).map(|mp| mp.pow_difficulty), Some(U256::from(20)));
+        assert!(jobs.get(other + 1).is_none());
+    }
+
+    #[test]
+    fn jobs_are_kept_until_the_head_is_two_behind() {
+        let mut jobs = Jobs::default();
+        jobs.new_head(H256::repeat_byte(1));
+        let first = jobs.issue(params(1, 10));
+        // However many rigs are mining, none of their jobs is lost within a head
+        for difficulty in 0..1000 {
+            jobs.issue(params(1, 100 + difficulty));
+        }
+        assert!(jobs.get(first).is_some());
+
+        jobs.new_head(H256::repeat_byte(2));
+        assert!(jobs.get(first).is_some());
+        let second = jobs.issue(params(2, 10));
+
+        jobs.new_head(H256::repeat_byte(3));
+        assert!(jobs.get(first).is_none());
+        assert!(jobs.get(second).is_some());
+    }
+
+    #[test]
+    fn processed_hashes_reject_duplicates() {
+        let mut processed = ProcessedHashes::default();
+        processed.new_head(H256::repeat_byte(1));
+
+        let mut insert = |pre_hash, obj_hash| processed.insert(H256::repeat_byte(pre_hash), H256::repeat_byte(obj_hash));
+        assert_eq!(insert(1, 10), ProcessedHash::Inserted);
+        assert_eq!(insert(1, 10), ProcessedHash::Duplicate);
+        assert_eq!(insert(1, 11), ProcessedHash::Inserted);
+        assert_eq!(insert(2, 10), ProcessedHash::UnknownHead);
+    }
+
+    #[test]
+    fn processed_hashes_survive_late_shares() {
+        let mut processed = ProcessedHashes::default();
+        processed.new_head(H256::repeat_byte(1));
+        processed.new_head(H256::repeat_byte(2));
+        let insert = |processed: &mut ProcessedHashes, pre_hash, obj_hash| {
+            processed.insert(H256::repeat_byte(pre_hash), H256::repeat_byte(obj_hash))
+        };
+        assert_eq!(insert(&mut processed, 2, 10), ProcessedHash::Inserted);
+
+        // A share validated after the new work does not wipe the new head's set
+        assert_eq!(insert(&mut processed, 1, 20), ProcessedHash::Inserted);
+        assert_eq!(insert(&mut processed, 2, 10), ProcessedHash::Duplicate);
+        assert_eq!(insert(&mut processed, 1, 20), ProcessedHash::Duplicate);
+
+        // Shares of an evicted head are stale, not duplicates
+        processed.new_head(H256::repeat_byte(3));
+        assert_eq!(insert(&mut processed, 1, 30), ProcessedHash::UnknownHead);
+        assert_eq!(insert(&mut processed, 2, 10), ProcessedHash::Duplicate);
+    }
+}
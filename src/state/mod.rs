@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+use jsonrpsee::core::async_trait;
+use primitive_types::{H256, U256};
+
+use crate::worker::MiningParams;
+
+pub(crate) mod local;
+pub(crate) mod redis_state;
+
+pub(crate) use local::LocalState;
+pub(crate) use redis_state::RedisState;
+
+/// Outcome of recording the hash of a submitted object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ProcessedHash {
+    /// First submission of the object for its chain head
+    Inserted,
+    /// Already submitted for this chain head
+    Duplicate,
+    /// The chain head is not tracked anymore, or never was
+    UnknownHead,
+}
+
+/// State that every proxy instance serving the same pool must agree on:
+/// the current work, the issued jobs, the submitted objects and the vardiff
+#[async_trait]
+pub(crate) trait SharedState: Send + Sync {
+    /// Work currently served to the rigs
+    async fn get_work(&self) -> anyhow::Result<Option<MiningParams>>;
+
+    async fn set_work(&self, mining_params: MiningParams) -> anyhow::Result<()>;
+
+    /// Returns the id of the job for these params, issuing a new one if needed
+    async fn issue_job(&self, mining_params: MiningParams) -> anyhow::Result<u64>;
+
+    async fn get_job(&self, job_id: u64) -> anyhow::Result<Option<MiningParams>>;
+
+    /// Records the object hash unless it was already submitted for this chain head.
+    /// The check and the insert are a single atomic step.
+    async fn insert_processed_hash(&self, pre_hash: H256, obj_hash: H256) -> anyhow::Result<ProcessedHash>;
+
+    async fn get_rig_difficulty(&self, wallet: &str, rig_name: &str) -> anyhow::Result<Option<U256>>;
+
+    /// Stores the rig's vardiff and returns the previous one
+    async fn set_rig_difficulty(&self, wallet: &str, rig_name: &str, difficulty: U256) -> anyhow::Result<Option<U256>>;
+
+    /// Takes the named lease for `holder`, or renews it when `holder` already has it.
+    /// False while another holder has it, a lease not renewed expires after `ttl`.
+    async fn acquire_lease(&self, name: &str, holder: &str, ttl: Duration) -> anyhow::Result<bool>;
+}
@@ -0,0 +1,274 @@
+use std::time::Duration;
+
+use codec::{Decode, Encode};
+use jsonrpsee::core::async_trait;
+use primitive_types::{H256, U256};
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+
+use crate::state::{ProcessedHash, SharedState};
+use crate::worker::MiningParams;
+
+/// Seconds a job is kept, long after its chain head is stale
+pub const JOB_TTL: usize = 60 * 60;
+/// Seconds the object hashes of a chain head are kept
+pub const PROCESSED_HASHES_TTL: usize = 60 * 60;
+/// Seconds the vardiff of a rig is kept after its last share
+pub const VARDIFF_TTL: usize = 24 * 60 * 60;
+
+/// Renews the lease when the holder has it, takes it when nobody has it
+const LEASE_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[2]) then
+    return 1
+end
+return 0
+"#;
+
+fn encode_work(mining_params: &MiningParams) -> Vec<u8> {
+    (
+        mining_params.pre_hash,
+        mining_params.parent_hash,
+        mining_params.win_difficulty,
+        mining_params.pow_difficulty,
+        mining_params.pub_key.to_bytes(),
+    )
+        .encode()
+}
+
+fn decode_work(encoded: &[u8]) -> anyhow::Result<MiningParams> {
+    let (pre_hash, parent_hash, win_difficulty, pow_difficulty, pub_key) =
+        <(H256, H256, U256, U256, [u8; 32])>::decode(&mut &encoded[..])?;
+
+    Ok(MiningParams {
+        pre_hash,
+        parent_hash,
+        win_difficulty,
+        pow_difficulty,
+        pub_key: ecies_ed25519::PublicKey::from_bytes(&pub_key)
+            .map_err(|e| anyhow::anyhow!("Invalid public key: {}", e))?,
+    })
+}
+
+/// State kept in Redis, shared by every proxy instance serving the pool.
+/// Keys are prefixed with the pool id.
+pub(crate) struct RedisState {
+    conn: ConnectionManager,
+    prefix: String,
+}
+
+impl RedisState {
+    pub(crate) async fn new(redis_url: &str, pool_id: &str) -> anyhow::Result<Self> {
+        let client = redis::Client::open(redis_url)?;
+
+        Ok(RedisState {
+            conn: ConnectionManager::new(client).await?,
+            prefix: format!("p3d:{}", pool_id),
+        })
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}:{}", self.prefix, name)
+    }
+
+    /// Names are hex encoded, whatever they contain two rigs never share a key
+    fn vardiff_key(&self, wallet: &str, rig_name: &str) -> String {
+        self.key(&format!("vardiff:{}:{}", hex::encode(wallet), hex::encode(rig_name)))
+    }
+}
+
+#[async_trait]
+impl SharedState for RedisState {
+    async fn get_work(&self) -> anyhow::Result<Option<MiningParams>> {
+        let mut conn = self.conn.clone();
+        let encoded: Option<Vec<u8>> = conn.get(self.key("work")).await?;
+        encoded.map(|encoded| decode_work(&encoded)).transpose()
+    }
+
+    async fn set_work(&self, mining_params: MiningParams) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        conn.set::<_, _, ()>(self.key("work"), encode_work(&mining_params)).await?;
+        Ok(())
+    }
+
+    async fn issue_job(&self, mining_params: MiningParams) -> anyhow::Result<u64> {
+        let mut conn = self.conn.clone();
+        let issued_key = self.key(&format!(
+            "issued:{:x}:{:x}",
+            mining_params.pre_hash, mining_params.pow_difficulty
+        ));
+        if let Some(job_id) = conn.get::<_, Option<u64>>(&issued_key).await? {
+            return Ok(job_id);
+        }
+
+        let job_id: u64 = conn.incr(self.key("job_id"), 1).await?;
+        conn.set_ex::<_, _, ()>(self.key(&format!("job:{}", job_id)), encode_work(&mining_params), JOB_TTL)
+            .await?;
+
+        // Another instance may have issued the same job meanwhile, the first one wins
+        let (issued, _): (bool, bool) = redis::pipe()
+            .atomic()
+            .set_nx(&issued_key, job_id)
+            .expire(&issued_key, JOB_TTL)
+            .query_async(&mut conn)
+            .await?;
+        if issued {
+            return Ok(job_id);
+        }
+        Ok(conn.get(&issued_key).await?)
+    }
+
+    async fn get_job(&self, job_id: u64) -> anyhow::Result<Option<MiningParams>> {
+        let mut conn = self.conn.clone();
+        let encoded: Option<Vec<u8>> = conn.get(self.key(&format!("job:{}", job_id))).await?;
+        encoded.map(|encoded| decode_work(&encoded)).transpose()
+    }
+
+    /// Every chain head has its own set, they expire instead of being evicted
+    async fn insert_processed_hash(&self, pre_hash: H256, obj_hash: H256) -> anyhow::Result<ProcessedHash> {
+        let mut conn = self.conn.clone();
+        let key = self.key(&format!("processed:{:x}", pre_hash));
+        let (added, _): (u64, bool) = redis::pipe()
+            .atomic()
+            .sadd(&key, obj_hash.as_bytes())
+            .expire(&key, PROCESSED_HASHES_TTL)
+            .query_async(&mut conn)
+            .await?;
+        Ok(if added == 1 {
+            ProcessedHash::Inserted
+        } else {
+            ProcessedHash::Duplicate
+        })
+    }
+
+    async fn get_rig_difficulty(&self, wallet: &str, rig_name: &str) -> anyhow::Result<Option<U256>> {
+        let mut conn = self.conn.clone();
+        let difficulty: Option<String> = conn.get(self.vardiff_key(wallet, rig_name)).await?;
+        Ok(difficulty.map(|d| U256::from_dec_str(&d)).transpose()?)
+    }
+
+    async fn set_rig_difficulty(&self, wallet: &str, rig_name: &str, difficulty: U256) -> anyhow::Result<Option<U256>> {
+        let mut conn = self.conn.clone();
+        let key = self.vardiff_key(wallet, rig_name);
+        let (previous,): (Option<String>,) = redis::pipe()
+            .atomic()
+            .get(&key)
+            .set_ex(&key, difficulty.to_string(), VARDIFF_TTL)
+            .ignore()
+            .query_async(&mut conn)
+            .await?;
+        Ok(previous.map(|d| U256::from_dec_str(&d)).transpose()?)
+    }
+
+    async fn acquire_lease(&self, name: &str, holder: &str, ttl: Duration) -> anyhow::Result<bool> {
+        let mut conn = self.conn.clone();
+        let acquired: u64 = redis::Script::new(LEASE_SCRIPT)
+            .key(self.key(&format!("lease:{}", name)))
+            .arg(holder)
+            .arg(ttl.as_millis() as u64)
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(acquired == 1)
+    }
+}
+
+/// Run against the Redis at REDIS_URL, as set by CI, and skipped without it
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    async fn state() -> Option<RedisState> {
+        let redis_url = match std::env::var("REDIS_URL") {
+            Ok(redis_url) => redis_url,
+            Err(_) => {
+                eprintln!("REDIS_URL is not set, skipped");
+                return None;
+            }
+        };
+        let state = RedisState::new(&redis_url, &format!("test-{}", Uuid::new_v4().simple()))
+            .await
+            .unwrap();
+        Some(state)
+    }
+
+    async fn clean(state: &RedisState) {
+        let mut conn = state.conn.clone();
+        let keys: Vec<String> = conn.keys(state.key("*")).await.unwrap();
+        if !keys.is_empty() {
+            conn.del::<_, ()>(keys).await.unwrap();
+        }
+    }
+
+    fn params(pre_hash: u8, pow_difficulty: u64) -> MiningParams {
+        let mut rng = rand::thread_rng();
+        let (_, pub_key) = ecies_ed25519::generate_keypair(&mut rng);
+        MiningParams {
+            pre_hash: H256::repeat_byte(pre_hash),
+            parent_hash: H256::repeat_byte(pre_hash + 1),
+            win_difficulty: U256::from(1000),
+            pow_difficulty: U256::from(pow_difficulty),
+            pub_key,
+        }
+    }
+
+    #[tokio::test]
+    async fn redis_state() {
+        let Some(state) = state().await else { return };
+
+        assert!(state.get_work().await.unwrap().is_none());
+        let mining_params = params(1, 10);
+        state.set_work(mining_params.clone()).await.unwrap();
+        let work = state.get_work().await.unwrap().unwrap();
+        assert_eq!(encode_work(&work), encode_work(&mining_params));
+
+        let job_id = state.issue_job(params(1, 10)).await.unwrap();
+        assert_eq!(state.issue_job(params(1, 10)).await.unwrap(), job_id);
+        assert_ne!(state.issue_job(params(1, 20)).await.unwrap(), job_id);
+        let job = state.get_job(job_id).await.unwrap().unwrap();
+        assert_eq!(job.pow_difficulty, U256::from(10));
+        assert!(state.get_job(job_id + 100).await.unwrap().is_none());
+
+        let insert = |pre_hash, obj_hash| state.insert_processed_hash(H256::repeat_byte(pre_hash), H256::repeat_byte(obj_hash));
+        assert_eq!(insert(1, 10).await.unwrap(), ProcessedHash::Inserted);
+        assert_eq!(insert(1, 10).await.unwrap(), ProcessedHash::Duplicate);
+        assert_eq!(insert(2, 10).await.unwrap(), ProcessedHash::Inserted);
+
+        assert!(state.get_rig_difficulty("wallet", "rig").await.unwrap().is_none());
+        assert!(state.set_rig_difficulty("wallet", "rig", U256::from(5)).await.unwrap().is_none());
+        assert_eq!(
+            state.set_rig_difficulty("wallet", "rig", U256::from(7)).await.unwrap(),
+            Some(U256::from(5))
+        );
+        assert_eq!(state.get_rig_difficulty("wallet", "rig").await.unwrap(), Some(U256::from(7)));
+        let ttl: i64 = state.conn.clone().ttl(state.vardiff_key("wallet", "rig")).await.unwrap();
+        assert!(ttl > 0 && ttl <= VARDIFF_TTL as i64);
+
+        // Names that would join into the same field are kept apart
+        state.set_rig_difficulty("a.b", "c", U256::from(1)).await.unwrap();
+        state.set_rig_difficulty("a", "b.c", U256::from(2)).await.unwrap();
+        assert_eq!(state.get_rig_difficulty("a.b", "c").await.unwrap(), Some(U256::from(1)));
+
+        clean(&state).await;
+    }
+
+    #[tokio::test]
+    async fn lease_has_one_holder() {
+        let Some(state) = state().await else { return };
+        let ttl = Duration::from_millis(200);
+
+        assert!(state.acquire_lease("blocks", "first", ttl).await.unwrap());
+        assert!(!state.acquire_lease("blocks", "second", ttl).await.unwrap());
+        // Renewed by its holder
+        assert!(state.acquire_lease("blocks", "first", ttl).await.unwrap());
+        assert!(state.acquire_lease("other", "second", ttl).await.unwrap());
+
+        tokio::time::sleep(ttl * 2).await;
+        assert!(state.acquire_lease("blocks", "second", ttl).await.unwrap());
+        assert!(!state.acquire_lease("blocks", "first", ttl).await.unwrap());
+
+        clean(&state).await;
+    }
+}
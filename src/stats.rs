@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+
+use mongodb::bson::DateTime;
+use primitive_types::U256;
+use serde::{Deserialize, Serialize};
+
+use crate::message::{Message, StatsPayload};
+use crate::pool_handler::{AppContex, Share};
+
+/// Windows used to estimate the hashrate, in seconds
+pub const HASHRATE_WINDOW_5M: i64 = 5 * 60;
+pub const HASHRATE_WINDOW_1H: i64 = 60 * 60;
+pub const HASHRATE_WINDOW_24H: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RigStats {
+    pub rig_name: String,
+    /// Shares accepted in the last 24h
+    pub shares: u64,
+    /// Sum of the difficulty of those shares
+    pub accepted_difficulty: U256,
+    /// Estimated hashes per second
+    pub hashrate_5m: f64,
+    pub hashrate_1h: f64,
+    pub hashrate_24h: f64,
+    /// Pool difficulty currently given to the rig
+    pub vardiff: Option<U256>,
+    pub last_share: Option<String>,
+    /// Last stats reported by the rig through push_stats
+    pub reported: Option<StatsPayload>,
+    pub reported_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MinerStats {
+    pub wallet: String,
+    pub rigs: Vec<RigStats>,
+    pub unpaid_balance: U256,
+}
+
+pub(crate) fn to_f64(value: U256) -> f64 {
+    value.to_string().parse().unwrap_or_default()
+}
+
+impl AppContex {
+    /// Per-rig statistics derived from the shares of the last 24h
+    pub(crate) async fn get_miner_stats(&self, wallet: String) -> anyhow::Result<MinerStats> {
+        let now = DateTime::now().timestamp_millis();
+        let since = DateTime::from_millis(now - HASHRATE_WINDOW_24H * 1000);
+        let shares = self.store.get_recent_shares(&wallet, since).await?;
+
+        let reports = self.store.get_reported_stats(&wallet, since).await?;
+
+        let mut by_rig: BTreeMap<String, (Vec<Share>, Option<Message>)> = BTreeMap::new();
+        for share in shares {
+            by_rig.entry(share.rig_name.clone()).or_default().0.push(share);
+        }
+        // Reports come oldest first, the last one wins
+        for report in reports {
+            let rig_name = report.payload.name.clone();
+            by_rig.entry(rig_name).or_default().1 = Some(report);
+        }
+
+        let mut rigs = Vec::new();
+        for (rig_name, (shares, report)) in by_rig {
+            let difficulty_since = |window: i64| {
+                shares
+                    .iter()
+                    .filter(|share| share.timestamp.timestamp_millis() >= now - window * 1000)
+                    .fold(U256::zero(), |sum, share| sum + share.difficulty)
+            };
+            let accepted_difficulty = difficulty_since(HASHRATE_WINDOW_24H);
+            let vardiff = self.state.get_rig_difficulty(&wallet, &rig_name).await?;
+            let last_share = shares
+                .iter()
+                .map(|share| share.timestamp)
+                .max()
+                .and_then(|timestamp| timestamp.try_to_rfc3339_string().ok());
+
+            rigs.push(RigStats {
+                shares: shares.len() as u64,
+                accepted_difficulty,
+                hashrate_5m: to_f64(difficulty_since(HASHRATE_WINDOW_5M)) / HASHRATE_WINDOW_5M as f64,
+                hashrate_1h: to_f64(difficulty_since(HASHRATE_WINDOW_1H)) / HASHRATE_WINDOW_1H as f64,
+                hashrate_24h: to_f64(accepted_difficulty) / HASHRATE_WINDOW_24H as f64,
+                vardiff,
+                last_share,
+                reported_at: report
+                    .as_ref()
+                    .and_then(|report| report.timestamp.try_to_rfc3339_string().ok()),
+                reported: report.map(|report| report.payload),
+                rig_name,
+            });
+        }
+
+        let unpaid_balance = self.store.get_unpaid_balance(&wallet).await?;
+
+        Ok(MinerStats {
+            wallet,
+            rigs,
+            unpaid_balance,
+        })
+    }
+}
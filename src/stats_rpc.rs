@@ -1,21 +1,29 @@
+use std::sync::Arc;
+
+use crate::error::ProxyError;
+use crate::pool_handler::AppContex;
+use crate::stats::MinerStats;
 use jsonrpsee::core::RpcResult;
 use jsonrpsee::core::async_trait;
 use jsonrpsee::proc_macros::rpc;
 
 #[rpc(server, client)]
 pub trait StatsRpc {
+	/// get_stats returns the share-derived statistics of a wallet, per rig
 	#[method(name = "get_stats")]
 	async fn get_stats(
 		&self,
         member_id: String,
-	) -> RpcResult<String>;
+	) -> RpcResult<MinerStats>;
 }
 
-pub struct StatsRpcServerImpl;
+pub struct StatsRpcServerImpl {
+    pub(crate) ctx: Arc<AppContex>,
+}
 
 impl StatsRpcServerImpl {
-    pub fn new() -> Self {
-        Self
+    pub fn new(ctx: Arc<AppContex>) -> Self {
+        Self { ctx }
     }
 }
 
@@ -24,7 +32,12 @@ impl StatsRpcServer for StatsRpcServerImpl {
 	async fn get_stats(
 		&self,
         member_id: String
-	) -> RpcResult<String> {
-		Ok(String::from(""))
+	) -> RpcResult<MinerStats> {
+		let response = self
+			.ctx
+			.get_miner_stats(member_id)
+			.await
+			.map_err(ProxyError::storage)?;
+		Ok(response)
 	}  
 }
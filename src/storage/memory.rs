@@ -0,0 +1,194 @@
+use std::sync::Mutex;
+
+use jsonrpsee::core::async_trait;
+use mongodb::bson::{oid::ObjectId, DateTime};
+use primitive_types::U256;
+
+use crate::blocks::{Block, BlockStatus};
+use crate::message::Message;
+use crate::payout::Balance;
+use crate::pool_handler::Share;
+use crate::storage::ShareStore;
+
+#[derive(Default)]
+struct Collections {
+    shares: Vec<Share>,
+    blocks: Vec<Block>,
+    balances: Vec<Balance>,
+    stats: Vec<Message>,
+}
+
+/// Keeps everything in memory. Meant for tests and small deployments,
+/// nothing survives a restart.
+#[derive(Default)]
+pub(crate) struct MemoryStore {
+    collections: Mutex<Collections>,
+}
+
+/// A wallet is credited at most once for each poscan_hash
+fn add_credits(ledger: &mut Vec<Balance>, balances: Vec<Balance>) {
+    for balance in balances {
+        let credited = ledger
+            .iter()
+            .any(|b| b.poscan_hash == balance.poscan_hash && b.miner_wallet == balance.miner_wallet);
+        if !credited {
+            ledger.push(balance);
+        }
+    }
+}
+
+/// Newest first, at most `limit` of them
+fn newest(mut shares: Vec<Share>, limit: u64) -> Vec<Share> {
+    shares.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    shares.truncate(limit as usize);
+    shares
+}
+
+#[async_trait]
+impl ShareStore for MemoryStore {
+    async fn insert_share(&self, share: Share) -> anyhow::Result<()> {
+        let share = Share {
+            id: Some(ObjectId::new()),
+            ..share
+        };
+        self.collections.lock().unwrap().shares.push(share);
+        Ok(())
+    }
+
+    async fn insert_credited_share(&self, share: Share, credit: Balance) -> anyhow::Result<()> {
+        let share = Share {
+            id: Some(ObjectId::new()),
+            ..share
+        };
+        let mut collections = self.collections.lock().unwrap();
+        collections.shares.push(share);
+        add_credits(&mut collections.balances, vec![credit]);
+        Ok(())
+    }
+
+    async fn get_rig_shares(&self, miner_wallet: &str, rig_name: &str, limit: u64) -> anyhow::Result<Vec<Share>> {
+        let shares = self
+            .collections
+            .lock()
+            .unwrap()
+            .shares
+            .iter()
+            .filter(|share| share.miner_wallet == miner_wallet && share.rig_name == rig_name)
+            .cloned()
+            .collect();
+        Ok(newest(shares, limit))
+    }
+
+    async fn get_recent_shares(&self, miner_wallet: &str, since: DateTime) -> anyhow::Result<Vec<Share>> {
+        Ok(self
+            .collections
+            .lock()
+            .unwrap()
+            .shares
+            .iter()
+            .filter(|share| share.miner_wallet == miner_wallet && share.timestamp >= since)
+            .cloned()
+            .collect())
+    }
+
+    async fn get_pplns_shares(&self, until: DateTime, window: u64) -> anyhow::Result<Vec<Share>> {
+        let shares = self
+            .collections
+            .lock()
+            .unwrap()
+            .shares
+            .iter()
+            .filter(|share| share.timestamp <= until)
+            .cloned()
+            .collect();
+        Ok(newest(shares, window))
+    }
+
+    async fn insert_block(&self, block: Block) -> anyhow::Result<()> {
+        self.collections.lock().unwrap().blocks.push(block);
+        Ok(())
+    }
+
+    async fn get_pending_blocks(&self) -> anyhow::Result<Vec<Block>> {
+        Ok(self
+            .collections
+            .lock()
+            .unwrap()
+            .blocks
+            .iter()
+            .filter(|block| block.status == BlockStatus::Pending)
+            .cloned()
+            .collect())
+    }
+
+    async fn update_block(&self, block: &Block) -> anyhow::Result<()> {
+        let mut collections = self.collections.lock().unwrap();
+        if let Some(stored) = collections.blocks.iter_mut().find(|stored| stored.poscan_hash == block.poscan_hash) {
+            stored.status = block.status;
+            stored.block_number = block.block_number;
+            stored.block_hash = block.block_hash;
+        }
+        Ok(())
+    }
+
+    async fn confirm_block(&self, block: &Block, balances: Vec<Balance>, shares: &[Share]) -> anyhow::Result<bool> {
+        let mut collections = self.collections.lock().unwrap();
+        let stored = collections
+            .blocks
+            .iter_mut()
+            .find(|stored| stored.poscan_hash == block.poscan_hash && stored.status == BlockStatus::Pending);
+        match stored {
+            Some(stored) => {
+                stored.status = BlockStatus::Confirmed;
+                stored.block_number = block.block_number;
+                stored.block_hash = block.block_hash;
+            }
+            None => return Ok(false),
+        }
+
+        add_credits(&mut collections.balances, balances);
+        let ids: Vec<_> = shares.iter().filter_map(|share| share.id).collect();
+        for share in collections.shares.iter_mut() {
+            if share.id.map(|id| ids.contains(&id)).unwrap_or(false) {
+                share.accounted = true;
+            }
+        }
+        Ok(true)
+    }
+
+    async fn credit_balances(&self, balances: Vec<Balance>) -> anyhow::Result<()> {
+        add_credits(&mut self.collections.lock().unwrap().balances, balances);
+        Ok(())
+    }
+
+    async fn get_unpaid_balance(&self, miner_wallet: &str) -> anyhow::Result<U256> {
+        Ok(self
+            .collections
+            .lock()
+            .unwrap()
+            .balances
+            .iter()
+            .filter(|balance| balance.miner_wallet == miner_wallet && !balance.paid)
+            .fold(U256::zero(), |unpaid, balance| unpaid + balance.amount))
+    }
+
+    async fn store_stats(&self, message: Message) -> anyhow::Result<String> {
+        let id = message.id.clone();
+        self.collections.lock().unwrap().stats.push(message);
+        Ok(id)
+    }
+
+    async fn get_reported_stats(&self, miner_wallet: &str, since: DateTime) -> anyhow::Result<Vec<Message>> {
+        let mut reports: Vec<Message> = self
+            .collections
+            .lock()
+            .unwrap()
+            .stats
+            .iter()
+            .filter(|message| message.channel == miner_wallet && message.timestamp >= since)
+            .cloned()
+            .collect();
+        reports.sort_by_key(|message| message.timestamp);
+        Ok(reports)
+    }
+}
@@ -0,0 +1,90 @@
+use std::str::FromStr;
+
+use jsonrpsee::core::async_trait;
+use mongodb::bson::DateTime;
+use primitive_types::U256;
+use serde::Deserialize;
+
+use crate::blocks::Block;
+use crate::message::Message;
+use crate::payout::Balance;
+use crate::pool_handler::Share;
+
+pub(crate) mod memory;
+pub(crate) mod mongo;
+pub(crate) mod sqlite;
+
+pub(crate) use memory::MemoryStore;
+pub(crate) use mongo::MongoStore;
+pub(crate) use sqlite::SqliteStore;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum StorageKind {
+    /// MongoDB at storage.mongo_url or MONGO_URL
+    Mongo,
+    /// Kept in memory, lost on restart
+    Memory,
+    /// Single SQLite file
+    Sqlite,
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        match kind {
+            "mongo" => Ok(StorageKind::Mongo),
+            "memory" => Ok(StorageKind::Memory),
+            "sqlite" => Ok(StorageKind::Sqlite),
+            _ => Err(format!("Unknown storage: {}", kind)),
+        }
+    }
+}
+
+/// Everything the pool persists: shares, blocks, balances and reported stats
+#[async_trait]
+pub(crate) trait ShareStore: Send + Sync {
+    async fn insert_share(&self, share: Share) -> anyhow::Result<()>;
+
+    /// Stores a PPS share together with its credit, a share is never
+    /// recorded as accounted without being credited
+    async fn insert_credited_share(&self, share: Share, credit: Balance) -> anyhow::Result<()>;
+
+    /// Last shares of a rig, newest first, accounted or not. Vardiff only
+    /// cares about when they were found, not whether they were paid.
+    async fn get_rig_shares(&self, miner_wallet: &str, rig_name: &str, limit: u64) -> anyhow::Result<Vec<Share>>;
+
+    /// Shares of a wallet submitted since the given time
+    async fn get_recent_shares(&self, miner_wallet: &str, since: DateTime) -> anyhow::Result<Vec<Share>>;
+
+    /// Last shares submitted up to the given time, newest first, accounted or not.
+    /// A share is rewarded by every block found until newer shares push it out of the window.
+    async fn get_pplns_shares(&self, until: DateTime, window: u64) -> anyhow::Result<Vec<Share>>;
+
+    async fn insert_block(&self, block: Block) -> anyhow::Result<()>;
+
+    async fn get_pending_blocks(&self) -> anyhow::Result<Vec<Block>>;
+
+    /// Updates the status of the block with the same poscan_hash. Several
+    /// candidates can be found for the same pre_hash, only one of them is final.
+    async fn update_block(&self, block: &Block) -> anyhow::Result<()>;
+
+    /// Credits the balances of a pending block, marks the shares it rewarded accounted
+    /// and marks it confirmed. False when the block was not pending anymore: another
+    /// pass or instance already paid it. A block left pending by a failure is paid by
+    /// the next pass, the credits it already wrote are not written twice.
+    async fn confirm_block(&self, block: &Block, balances: Vec<Balance>, shares: &[Share]) -> anyhow::Result<bool>;
+
+    /// Credits the balances, skipping those whose wallet was already credited for the same poscan_hash
+    async fn credit_balances(&self, balances: Vec<Balance>) -> anyhow::Result<()>;
+
+    /// Sum of the credits not paid yet to a wallet
+    async fn get_unpaid_balance(&self, miner_wallet: &str) -> anyhow::Result<U256>;
+
+    /// Stores a miner-reported stats message and returns its id
+    async fn store_stats(&self, message: Message) -> anyhow::Result<String>;
+
+    /// Stats reported by the wallet's rigs since the given time, oldest first
+    async fn get_reported_stats(&self, miner_wallet: &str, since: DateTime) -> anyhow::Result<Vec<Message>>;
+}
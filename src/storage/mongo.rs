@@ -0,0 +1,197 @@
+use std::future::Future;
+use std::time::Instant;
+
+use jsonrpsee::core::async_trait;
+use mongodb::bson::{doc, to_bson, to_document, DateTime, Document};
+use mongodb::options::{ClientOptions, FindOptions, UpdateOptions};
+use mongodb::{Client as ClientMongo, Collection};
+use primitive_types::U256;
+use serde::de::DeserializeOwned;
+
+use crate::blocks::{Block, BlockStatus};
+use crate::message::Message;
+use crate::metrics;
+use crate::payout::Balance;
+use crate::pool_handler::Share;
+use crate::storage::ShareStore;
+
+pub const SHARES_COLLECTION: &str = "shares";
+pub const BLOCKS_COLLECTION: &str = "blocks";
+pub const BALANCES_COLLECTION: &str = "balances";
+pub const STATS_COLLECTION: &str = "stats";
+
+/// Runs a write and records how long Mongo took
+async fn write<T>(collection: &'static str, write: impl Future<Output = mongodb::error::Result<T>>) -> anyhow::Result<T> {
+    let started = Instant::now();
+    let result = write.await;
+    metrics::mongo_write(collection, started.elapsed());
+    Ok(result?)
+}
+
+pub(crate) struct MongoStore {
+    mongo: ClientMongo,
+    db_name: String,
+}
+
+impl MongoStore {
+    pub(crate) async fn new(mongo_addr: &str, db_name: &str) -> anyhow::Result<Self> {
+        let client_options = ClientOptions::parse(mongo_addr).await?;
+
+        Ok(MongoStore {
+            mongo: ClientMongo::with_options(client_options)?,
+            db_name: db_name.to_string(),
+        })
+    }
+
+    fn collection<T>(&self, coll_name: &str) -> Collection<T> {
+        self.mongo.database(&self.db_name).collection::<T>(coll_name)
+    }
+
+    async fn find<T>(
+        &self,
+        coll_name: &str,
+        filter: Document,
+        find_options: impl Into<Option<FindOptions>>,
+    ) -> anyhow::Result<Vec<T>>
+    where
+        T: DeserializeOwned + Unpin + Send + Sync,
+    {
+        let mut cursor = self.collection::<T>(coll_name).find(filter, find_options).await?;
+
+        let mut result = Vec::new();
+
+        while cursor.advance().await? {
+            result.push(cursor.deserialize_current()?);
+        }
+
+        Ok(result)
+    }
+}
+
+#[async_trait]
+impl ShareStore for MongoStore {
+    async fn insert_share(&self, share: Share) -> anyhow::Result<()> {
+        write(SHARES_COLLECTION, self.collection::<Share>(SHARES_COLLECTION).insert_one(share, None)).await?;
+        Ok(())
+    }
+
+    /// Without a transaction the credit goes first: a failure after it leaves a
+    /// credited share unrecorded, never a recorded share that was not credited
+    async fn insert_credited_share(&self, share: Share, credit: Balance) -> anyhow::Result<()> {
+        self.credit_balances(vec![credit]).await?;
+        self.insert_share(share).await
+    }
+
+    async fn get_rig_shares(&self, miner_wallet: &str, rig_name: &str, limit: u64) -> anyhow::Result<Vec<Share>> {
+        let filter = doc! {"miner_wallet": miner_wallet, "rig_name": rig_name};
+        let find_options = FindOptions::builder()
+            .sort(doc! { "timestamp": -1 })
+            .limit(limit as i64)
+            .build();
+        self.find(SHARES_COLLECTION, filter, find_options).await
+    }
+
+    async fn get_recent_shares(&self, miner_wallet: &str, since: DateTime) -> anyhow::Result<Vec<Share>> {
+        let filter = doc! {"miner_wallet": miner_wallet, "timestamp": {"$gte": since}};
+        self.find(SHARES_COLLECTION, filter, None).await
+    }
+
+    async fn get_pplns_shares(&self, until: DateTime, window: u64) -> anyhow::Result<Vec<Share>> {
+        let filter = doc! {"timestamp": {"$lte": until}};
+        let find_options = FindOptions::builder()
+            .sort(doc! { "timestamp": -1 })
+            .limit(window as i64)
+            .build();
+        self.find(SHARES_COLLECTION, filter, find_options).await
+    }
+
+    async fn insert_block(&self, block: Block) -> anyhow::Result<()> {
+        write(BLOCKS_COLLECTION, self.collection::<Block>(BLOCKS_COLLECTION).insert_one(block, None)).await?;
+        Ok(())
+    }
+
+    async fn get_pending_blocks(&self) -> anyhow::Result<Vec<Block>> {
+        let filter = doc! {"status": to_bson(&BlockStatus::Pending)?};
+        self.find(BLOCKS_COLLECTION, filter, None).await
+    }
+
+    async fn update_block(&self, block: &Block) -> anyhow::Result<()> {
+        let filter = doc! {"poscan_hash": to_bson(&block.poscan_hash)?};
+        let update = doc! {"$set": {
+            "status": to_bson(&block.status)?,
+            "block_number": to_bson(&block.block_number)?,
+            "block_hash": to_bson(&block.block_hash)?,
+        }};
+        write(BLOCKS_COLLECTION, self.collection::<Block>(BLOCKS_COLLECTION).update_one(filter, update, None)).await?;
+        Ok(())
+    }
+
+    /// Transactions need a replica set, so every write can be repeated and the
+    /// status change goes last: a failure before it leaves the block pending, and
+    /// the next pass writes the same credits again without paying twice.
+    async fn confirm_block(&self, block: &Block, balances: Vec<Balance>, shares: &[Share]) -> anyhow::Result<bool> {
+        let pending = doc! {
+            "poscan_hash": to_bson(&block.poscan_hash)?,
+            "status": to_bson(&BlockStatus::Pending)?,
+        };
+        let blocks = self.collection::<Block>(BLOCKS_COLLECTION);
+        if blocks.count_documents(pending.clone(), None).await? == 0 {
+            return Ok(false);
+        }
+
+        self.credit_balances(balances).await?;
+        let ids: Vec<_> = shares.iter().filter_map(|share| share.id).collect();
+        write(
+            SHARES_COLLECTION,
+            self.collection::<Share>(SHARES_COLLECTION).update_many(
+                doc! {"_id": {"$in": ids}},
+                doc! {"$set": {"accounted": true}},
+                None,
+            ),
+        )
+        .await?;
+
+        let update = doc! {"$set": {
+            "status": to_bson(&BlockStatus::Confirmed)?,
+            "block_number": to_bson(&block.block_number)?,
+            "block_hash": to_bson(&block.block_hash)?,
+        }};
+        let confirmed = write(BLOCKS_COLLECTION, blocks.update_one(pending, update, None)).await?;
+        Ok(confirmed.modified_count > 0)
+    }
+
+    /// Upserts keyed on (poscan_hash, miner_wallet), a credit written again is left as it was
+    async fn credit_balances(&self, balances: Vec<Balance>) -> anyhow::Result<()> {
+        let balances_collection = self.collection::<Balance>(BALANCES_COLLECTION);
+        for balance in balances {
+            let filter = doc! {
+                "poscan_hash": to_bson(&balance.poscan_hash)?,
+                "miner_wallet": &balance.miner_wallet,
+            };
+            let update = doc! {"$setOnInsert": to_document(&balance)?};
+            let options = UpdateOptions::builder().upsert(true).build();
+            write(BALANCES_COLLECTION, balances_collection.update_one(filter, update, options)).await?;
+        }
+        Ok(())
+    }
+
+    async fn get_unpaid_balance(&self, miner_wallet: &str) -> anyhow::Result<U256> {
+        let filter = doc! {"miner_wallet": miner_wallet, "paid": false};
+        let balances: Vec<Balance> = self.find(BALANCES_COLLECTION, filter, None).await?;
+        Ok(balances.iter().fold(U256::zero(), |unpaid, balance| unpaid + balance.amount))
+    }
+
+    async fn store_stats(&self, message: Message) -> anyhow::Result<String> {
+        let id = message.id.clone();
+        write(STATS_COLLECTION, self.collection::<Message>(STATS_COLLECTION).insert_one(message, None)).await?;
+        Ok(id)
+    }
+
+    async fn get_reported_stats(&self, miner_wallet: &str, since: DateTime) -> anyhow::Result<Vec<Message>> {
+        let filter = doc! {"channel": miner_wallet, "timestamp": {"$gte": since}};
+        let find_options = FindOptions::builder()
+            .sort(doc! { "timestamp": 1 })
+            .build();
+        self.find(STATS_COLLECTION, filter, find_options).await
+    }
+}
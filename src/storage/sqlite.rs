@@ -0,0 +1,561 @@
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use jsonrpsee::core::async_trait;
+use mongodb::bson::{oid::ObjectId, DateTime};
+use primitive_types::{H256, U256};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection};
+
+use crate::blocks::{Block, BlockStatus};
+use crate::message::Message;
+use crate::payout::Balance;
+use crate::pool_handler::Share;
+use crate::storage::ShareStore;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS shares (
+        id TEXT PRIMARY KEY,
+        miner_wallet TEXT NOT NULL,
+        rig_name TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        difficulty TEXT NOT NULL,
+        accounted INTEGER NOT NULL,
+        paid INTEGER NOT NULL,
+        block_candidate INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS shares_rig ON shares (miner_wallet, rig_name, timestamp);
+    CREATE INDEX IF NOT EXISTS shares_timestamp ON shares (timestamp);
+
+    CREATE TABLE IF NOT EXISTS blocks (
+        poscan_hash TEXT PRIMARY KEY,
+        pre_hash TEXT NOT NULL,
+        parent_hash TEXT NOT NULL,
+        miner_wallet TEXT NOT NULL,
+        rig_name TEXT NOT NULL,
+        difficulty TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        status TEXT NOT NULL,
+        block_number INTEGER,
+        block_hash TEXT
+    );
+
+    CREATE TABLE IF NOT EXISTS balances (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        miner_wallet TEXT NOT NULL,
+        amount TEXT NOT NULL,
+        pre_hash TEXT NOT NULL,
+        poscan_hash TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        paid INTEGER NOT NULL,
+        UNIQUE (poscan_hash, miner_wallet)
+    );
+    CREATE INDEX IF NOT EXISTS balances_wallet ON balances (miner_wallet, paid);
+
+    CREATE TABLE IF NOT EXISTS stats (
+        id TEXT PRIMARY KEY,
+        channel TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        payload TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS stats_channel ON stats (channel, timestamp);
+";
+
+const SHARE_COLUMNS: &str =
+    "id, miner_wallet, rig_name, timestamp, difficulty, accounted, paid, block_candidate";
+
+type ShareRow = (String, String, String, i64, String, bool, bool, bool);
+
+fn share_from_row(row: ShareRow) -> anyhow::Result<Share> {
+    let (id, miner_wallet, rig_name, timestamp, difficulty, accounted, paid, block_candidate) = row;
+    Ok(Share {
+        id: Some(ObjectId::parse_str(id)?),
+        miner_wallet,
+        rig_name,
+        timestamp: DateTime::from_millis(timestamp),
+        difficulty: U256::from_dec_str(&difficulty)?,
+        accounted,
+        paid,
+        block_candidate,
+    })
+}
+
+fn status_to_str(status: BlockStatus) -> &'static str {
+    match status {
+        BlockStatus::Pending => "pending",
+        BlockStatus::Confirmed => "confirmed",
+        BlockStatus::Orphaned => "orphaned",
+    }
+}
+
+fn status_from_str(status: &str) -> anyhow::Result<BlockStatus> {
+    match status {
+        "pending" => Ok(BlockStatus::Pending),
+        "confirmed" => Ok(BlockStatus::Confirmed),
+        "orphaned" => Ok(BlockStatus::Orphaned),
+        _ => anyhow::bail!("Unknown block status: {}", status),
+    }
+}
+
+fn insert_share_row(conn: &Connection, share: &Share) -> anyhow::Result<()> {
+    conn.execute(
+        &format!("INSERT INTO shares ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)", SHARE_COLUMNS),
+        params![
+            share.id.unwrap_or_else(ObjectId::new).to_hex(),
+            share.miner_wallet,
+            share.rig_name,
+            share.timestamp.timestamp_millis(),
+            share.difficulty.to_string(),
+            share.accounted,
+            share.paid,
+            share.block_candidate,
+        ],
+    )?;
+    Ok(())
+}
+
+/// A wallet already credited for the same poscan_hash is skipped
+fn insert_balances(conn: &Connection, balances: &[Balance]) -> anyhow::Result<()> {
+    for balance in balances.iter() {
+        conn.execute(
+            "INSERT OR IGNORE INTO balances (miner_wallet, amount, pre_hash, poscan_hash, timestamp, paid)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                balance.miner_wallet,
+                balance.amount.to_string(),
+                format!("{:?}", balance.pre_hash),
+                format!("{:?}", balance.poscan_hash),
+                balance.timestamp.timestamp_millis(),
+                balance.paid,
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// Keeps everything in a single SQLite file, for operators who don't want to run MongoDB
+pub(crate) struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    pub(crate) fn new(path: &str) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+
+        Ok(SqliteStore {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs a query on the blocking pool, away from the tasks of the runtime
+    /// whatever its flavor
+    async fn with_conn<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> anyhow::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap())).await?
+    }
+
+    async fn query_shares(&self, filter: &'static str, params: Vec<Value>) -> anyhow::Result<Vec<Share>> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(&format!("SELECT {} FROM shares WHERE {}", SHARE_COLUMNS, filter))?;
+            let rows = stmt.query_map(params_from_iter(params), |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                    row.get(6)?,
+                    row.get(7)?,
+                ))
+            })?;
+
+            let mut result = Vec::new();
+            for row in rows {
+                result.push(share_from_row(row?)?);
+            }
+            Ok(result)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl ShareStore for SqliteStore {
+    async fn insert_share(&self, share: Share) -> anyhow::Result<()> {
+        self.with_conn(move |conn| insert_share_row(conn, &share)).await
+    }
+
+    async fn insert_credited_share(&self, share: Share, credit: Balance) -> anyhow::Result<()> {
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            insert_share_row(&tx, &share)?;
+            insert_balances(&tx, &[credit])?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_rig_shares(&self, miner_wallet: &str, rig_name: &str, limit: u64) -> anyhow::Result<Vec<Share>> {
+        self.query_shares(
+            "miner_wallet = ?1 AND rig_name = ?2 ORDER BY timestamp DESC LIMIT ?3",
+            vec![miner_wallet.into(), rig_name.into(), (limit as i64).into()],
+        )
+        .await
+    }
+
+    async fn get_recent_shares(&self, miner_wallet: &str, since: DateTime) -> anyhow::Result<Vec<Share>> {
+        self.query_shares(
+            "miner_wallet = ?1 AND timestamp >= ?2",
+            vec![miner_wallet.into(), since.timestamp_millis().into()],
+        )
+        .await
+    }
+
+    async fn get_pplns_shares(&self, until: DateTime, window: u64) -> anyhow::Result<Vec<Share>> {
+        self.query_shares(
+            "timestamp <= ?1 ORDER BY timestamp DESC LIMIT ?2",
+            vec![until.timestamp_millis().into(), (window as i64).into()],
+        )
+        .await
+    }
+
+    async fn insert_block(&self, block: Block) -> anyhow::Result<()> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO blocks (pre_hash, parent_hash, poscan_hash, miner_wallet, rig_name, difficulty, timestamp, status, block_number, block_hash)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    format!("{:?}", block.pre_hash),
+                    format!("{:?}", block.parent_hash),
+                    format!("{:?}", block.poscan_hash),
+                    block.miner_wallet,
+                    block.rig_name,
+                    block.difficulty.to_string(),
+                    block.timestamp.timestamp_millis(),
+                    status_to_str(block.status),
+                    block.block_number.map(|number| number as i64),
+                    block.block_hash.map(|hash| format!("{:?}", hash)),
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_pending_blocks(&self) -> anyhow::Result<Vec<Block>> {
+        type BlockRow = (String, String, String, String, String, String, i64, String, Option<i64>, Option<String>);
+
+        let rows: Vec<BlockRow> = self.with_conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT pre_hash, parent_hash, poscan_hash, miner_wallet, rig_name, difficulty, timestamp, status, block_number, block_hash
+                 FROM blocks WHERE status = ?1",
+            )?;
+            let rows = stmt.query_map(params![status_to_str(BlockStatus::Pending)], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                    row.get(6)?,
+                    row.get(7)?,
+                    row.get(8)?,
+                    row.get(9)?,
+                ))
+            })?;
+            Ok(rows.collect::<Result<_, _>>()?)
+        })
+        .await?;
+
+        let mut result = Vec::new();
+        for (pre_hash, parent_hash, poscan_hash, miner_wallet, rig_name, difficulty, timestamp, status, block_number, block_hash) in rows {
+            result.push(Block {
+                pre_hash: H256::from_str(&pre_hash)?,
+                parent_hash: H256::from_str(&parent_hash)?,
+                poscan_hash: H256::from_str(&poscan_hash)?,
+                miner_wallet,
+                rig_name,
+                difficulty: U256::from_dec_str(&difficulty)?,
+                timestamp: DateTime::from_millis(timestamp),
+                status: status_from_str(&status)?,
+                block_number: block_number.map(|number| number as u64),
+                block_hash: block_hash.map(|hash| H256::from_str(&hash)).transpose()?,
+            });
+        }
+        Ok(result)
+    }
+
+    async fn update_block(&self, block: &Block) -> anyhow::Result<()> {
+        let block = block.clone();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE blocks SET status = ?1, block_number = ?2, block_hash = ?3 WHERE poscan_hash = ?4",
+                params![
+                    status_to_str(block.status),
+                    block.block_number.map(|number| number as i64),
+                    block.block_hash.map(|hash| format!("{:?}", hash)),
+                    format!("{:?}", block.poscan_hash),
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn confirm_block(&self, block: &Block, balances: Vec<Balance>, shares: &[Share]) -> anyhow::Result<bool> {
+        let block = block.clone();
+        let ids: Vec<_> = shares.iter().filter_map(|share| share.id).collect();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let confirmed = tx.execute(
+                "UPDATE blocks SET status = ?1, block_number = ?2, block_hash = ?3 WHERE poscan_hash = ?4 AND status = ?5",
+                params![
+                    status_to_str(BlockStatus::Confirmed),
+                    block.block_number.map(|number| number as i64),
+                    block.block_hash.map(|hash| format!("{:?}", hash)),
+                    format!("{:?}", block.poscan_hash),
+                    status_to_str(BlockStatus::Pending),
+                ],
+            )?;
+            // Rolled back when the transaction is dropped
+            if confirmed == 0 {
+                return Ok(false);
+            }
+
+            insert_balances(&tx, &balances)?;
+            for id in ids {
+                tx.execute("UPDATE shares SET accounted = 1 WHERE id = ?1", params![id.to_hex()])?;
+            }
+            tx.commit()?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn credit_balances(&self, balances: Vec<Balance>) -> anyhow::Result<()> {
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            insert_balances(&tx, &balances)?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_unpaid_balance(&self, miner_wallet: &str) -> anyhow::Result<U256> {
+        let miner_wallet = miner_wallet.to_string();
+        let amounts: Vec<String> = self
+            .with_conn(move |conn| {
+                let mut stmt = conn.prepare("SELECT amount FROM balances WHERE miner_wallet = ?1 AND paid = 0")?;
+                let rows = stmt.query_map(params![miner_wallet], |row| row.get(0))?;
+                Ok(rows.collect::<Result<_, _>>()?)
+            })
+            .await?;
+
+        let mut unpaid = U256::zero();
+        for amount in amounts {
+            unpaid += U256::from_dec_str(&amount)?;
+        }
+        Ok(unpaid)
+    }
+
+    async fn store_stats(&self, message: Message) -> anyhow::Result<String> {
+        let payload = serde_json::to_string(&message.payload)?;
+        let id = message.id.clone();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO stats (id, channel, timestamp, payload) VALUES (?1, ?2, ?3, ?4)",
+                params![message.id, message.channel, message.timestamp.timestamp_millis(), payload],
+            )?;
+            Ok(())
+        })
+        .await?;
+        Ok(id)
+    }
+
+    async fn get_reported_stats(&self, miner_wallet: &str, since: DateTime) -> anyhow::Result<Vec<Message>> {
+        let miner_wallet = miner_wallet.to_string();
+        let rows: Vec<(String, String, i64, String)> = self
+            .with_conn(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, channel, timestamp, payload FROM stats
+                     WHERE channel = ?1 AND timestamp >= ?2 ORDER BY timestamp ASC",
+                )?;
+                let rows = stmt.query_map(params![miner_wallet, since.timestamp_millis()], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+                })?;
+                Ok(rows.collect::<Result<_, _>>()?)
+            })
+            .await?;
+
+        let mut result = Vec::new();
+        for (id, channel, timestamp, payload) in rows {
+            result.push(Message {
+                id,
+                channel,
+                timestamp: DateTime::from_millis(timestamp),
+                payload: serde_json::from_str(&payload)?,
+            });
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::StatsPayload;
+    use crate::pool_handler::tests::share;
+
+    fn store() -> SqliteStore {
+        SqliteStore::new(":memory:").unwrap()
+    }
+
+    fn block(poscan_hash: u8) -> Block {
+        Block {
+            pre_hash: H256::repeat_byte(1),
+            parent_hash: H256::repeat_byte(2),
+            poscan_hash: H256::repeat_byte(poscan_hash),
+            miner_wallet: String::from("wallet"),
+            rig_name: String::from("rig"),
+            difficulty: U256::from(1000),
+            timestamp: DateTime::now(),
+            status: BlockStatus::Pending,
+            block_number: None,
+            block_hash: None,
+        }
+    }
+
+    fn balance(wallet: &str, amount: u64, block: &Block) -> Balance {
+        Balance {
+            miner_wallet: wallet.to_string(),
+            amount: U256::from(amount),
+            pre_hash: block.pre_hash,
+            poscan_hash: block.poscan_hash,
+            timestamp: DateTime::now(),
+            paid: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn shares_round_trip() {
+        let store = store();
+        store.insert_share(share("wallet", "rig", 3000, 10)).await.unwrap();
+        store.insert_share(share("wallet", "rig", 1000, 30)).await.unwrap();
+        store.insert_share(share("wallet", "other", 2000, 20)).await.unwrap();
+        store.insert_share(share("another", "rig", 500, 40)).await.unwrap();
+
+        let rig_shares = store.get_rig_shares("wallet", "rig", 10).await.unwrap();
+        let difficulties: Vec<_> = rig_shares.iter().map(|share| share.difficulty.as_u64()).collect();
+        assert_eq!(difficulties, vec![30, 10]);
+        assert!(rig_shares.iter().all(|share| share.id.is_some() && !share.accounted));
+        assert_eq!(store.get_rig_shares("wallet", "rig", 1).await.unwrap().len(), 1);
+
+        let since = DateTime::from_millis(DateTime::now().timestamp_millis() - 2500);
+        assert_eq!(store.get_recent_shares("wallet", since).await.unwrap().len(), 2);
+
+        let until = DateTime::from_millis(DateTime::now().timestamp_millis() - 800);
+        let pplns: Vec<_> = store
+            .get_pplns_shares(until, 2)
+            .await
+            .unwrap()
+            .iter()
+            .map(|share| share.difficulty.as_u64())
+            .collect();
+        assert_eq!(pplns, vec![30, 20]);
+    }
+
+    #[tokio::test]
+    async fn blocks_are_keyed_on_poscan_hash() {
+        let store = store();
+        // Two candidates of the same chain head
+        store.insert_block(block(3)).await.unwrap();
+        store.insert_block(block(4)).await.unwrap();
+        assert!(store.insert_block(block(3)).await.is_err());
+
+        let orphaned = Block {
+            status: BlockStatus::Orphaned,
+            block_number: Some(7),
+            block_hash: Some(H256::repeat_byte(5)),
+            ..block(4)
+        };
+        store.update_block(&orphaned).await.unwrap();
+
+        let pending = store.get_pending_blocks().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        let stored = &pending[0];
+        assert_eq!(stored.poscan_hash, H256::repeat_byte(3));
+        assert_eq!(stored.pre_hash, H256::repeat_byte(1));
+        assert_eq!(stored.parent_hash, H256::repeat_byte(2));
+        assert_eq!(stored.difficulty, U256::from(1000));
+        assert_eq!(stored.block_number, None);
+    }
+
+    #[tokio::test]
+    async fn block_is_confirmed_once() {
+        let store = store();
+        store.insert_share(share("alice", "rig", 1000, 10)).await.unwrap();
+        let shares = store.get_rig_shares("alice", "rig", 10).await.unwrap();
+        let pending = block(3);
+        store.insert_block(pending.clone()).await.unwrap();
+
+        let confirmed = Block {
+            status: BlockStatus::Confirmed,
+            block_number: Some(7),
+            block_hash: Some(H256::repeat_byte(5)),
+            ..pending
+        };
+        let balances = vec![balance("alice", 600, &confirmed), balance("bob", 400, &confirmed)];
+        assert!(store.confirm_block(&confirmed, balances.clone(), &shares).await.unwrap());
+        assert!(store.get_pending_blocks().await.unwrap().is_empty());
+        assert!(store.get_rig_shares("alice", "rig", 10).await.unwrap()[0].accounted);
+        // Still in the window of the next block
+        assert_eq!(store.get_pplns_shares(DateTime::now(), 10).await.unwrap().len(), 1);
+
+        assert!(!store.confirm_block(&confirmed, balances.clone(), &shares).await.unwrap());
+        store.credit_balances(balances).await.unwrap();
+        assert_eq!(store.get_unpaid_balance("alice").await.unwrap(), U256::from(600));
+        assert_eq!(store.get_unpaid_balance("bob").await.unwrap(), U256::from(400));
+    }
+
+    #[tokio::test]
+    async fn credited_share_is_stored_with_its_credit() {
+        let store = store();
+        let credited = Share {
+            accounted: true,
+            ..share("wallet", "rig", 1000, 10)
+        };
+        store.insert_credited_share(credited, balance("wallet", 25, &block(3))).await.unwrap();
+
+        assert!(store.get_rig_shares("wallet", "rig", 10).await.unwrap()[0].accounted);
+        assert_eq!(store.get_unpaid_balance("wallet").await.unwrap(), U256::from(25));
+    }
+
+    #[tokio::test]
+    async fn stats_round_trip() {
+        let store = store();
+        let payload = StatsPayload {
+            name: String::from("rig"),
+            cores: String::from("8"),
+            tag: String::from("tag"),
+            hashrate: String::from("100"),
+            good_hashrate: String::from("90"),
+        };
+        let id = store.store_stats(Message::new(String::from("wallet"), payload)).await.unwrap();
+
+        let since = DateTime::from_millis(DateTime::now().timestamp_millis() - 1000);
+        let reports = store.get_reported_stats("wallet", since).await.unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].id, id);
+        assert_eq!(reports[0].payload.good_hashrate, "90");
+        assert!(store.get_reported_stats("other", since).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,230 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::StreamExt;
+use jsonrpsee::core::JsonValue;
+use primitive_types::U256;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio_util::codec::{FramedRead, LinesCodec};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::error::ProxyError;
+use crate::pool_handler::AppContex;
+use crate::worker::MiningParams;
+
+/// Rig name used when the miner authorizes with a bare wallet
+pub const DEFAULT_RIG_NAME: &str = "default";
+/// Longest line a session may send, the JSON-RPC server's request limit.
+/// Longer lines close the session.
+pub const MAX_LINE_LENGTH: usize = 10 * 1024 * 1024;
+
+#[derive(Deserialize)]
+struct StratumRequest {
+    #[serde(default)]
+    id: JsonValue,
+    method: String,
+    #[serde(default)]
+    params: Vec<JsonValue>,
+}
+
+struct Session {
+    id: String,
+    subscribed: bool,
+    worker: Option<(String, String)>,
+    difficulty: Option<U256>,
+}
+
+/// Starts a newline-delimited JSON server for Stratum-style miners.
+/// Sessions share the same backend as the JSON-RPC server.
+pub(crate) async fn stratum_server(ctx: Arc<AppContex>, address: String) -> anyhow::Result<SocketAddr> {
+    let socket_url: SocketAddr = address.parse::<SocketAddr>()?;
+    let listener = TcpListener::bind(socket_url).await?;
+    let addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let ctx = ctx.clone();
+                    let work_rx = ctx.new_work.subscribe();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_session(ctx, stream, work_rx).await {
+                            warn!(%peer, error = %e, "🚩 Stratum session closed");
+                        }
+                    });
+                }
+                Err(e) => error!(error = %e, "🚩 Stratum accept failed"),
+            }
+        }
+    });
+
+    Ok(addr)
+}
+
+async fn handle_session(
+    ctx: Arc<AppContex>,
+    stream: TcpStream,
+    mut work_rx: broadcast::Receiver<MiningParams>,
+) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = FramedRead::new(reader, LinesCodec::new_with_max_length(MAX_LINE_LENGTH));
+    let mut session = Session {
+        id: Uuid::new_v4().simple().to_string(),
+        subscribed: false,
+        worker: None,
+        difficulty: None,
+    };
+
+    loop {
+        tokio::select! {
+            line = lines.next() => {
+                let line = match line {
+                    Some(line) => line?,
+                    None => return Ok(()),
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                for message in handle_request(&ctx, &mut session, &line).await {
+                    writer.write_all(format!("{}\n", message).as_bytes()).await?;
+                }
+            }
+            work = work_rx.recv() => {
+                let mining_params = match work {
+                    Ok(mining_params) => mining_params,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                };
+                if session.subscribed && session.worker.is_some() {
+                    for message in work_messages(&ctx, &mut session, &mining_params).await {
+                        writer.write_all(format!("{}\n", message).as_bytes()).await?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn handle_request(ctx: &AppContex, session: &mut Session, line: &str) -> Vec<JsonValue> {
+    let request: StratumRequest = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(_) => return vec![error_response(JsonValue::Null, 20, "Malformed request")],
+    };
+    let id = request.id;
+
+    match request.method.as_str() {
+        "mining.subscribe" => {
+            session.subscribed = true;
+            vec![json!({
+                "id": id,
+                "result": [[["mining.notify", session.id], ["mining.set_difficulty", session.id]], session.id],
+                "error": null,
+            })]
+        }
+        "mining.authorize" => {
+            let username = match request.params.first().and_then(|p| p.as_str()) {
+                Some(username) if !username.is_empty() => username,
+                _ => return vec![error_response(id, 24, "Missing worker name")],
+            };
+            let (wallet, rig_name) = match username.split_once('.') {
+                Some((wallet, rig_name)) => (wallet.to_string(), rig_name.to_string()),
+                None => (username.to_string(), DEFAULT_RIG_NAME.to_string()),
+            };
+            info!(%wallet, rig = %rig_name, "⛏️  Stratum worker authorized");
+            session.worker = Some((wallet, rig_name));
+
+            let mut messages = vec![json!({ "id": id, "result": true, "error": null })];
+            // Without work yet, the session gets it from the chain head watcher
+            let cached = ctx.current_work().await.ok().flatten();
+            if let (true, Some(mining_params)) = (session.subscribed, cached) {
+                messages.extend(work_messages(ctx, session, &mining_params).await);
+            }
+            messages
+        }
+        "mining.submit" => {
+            let (wallet, rig_name) = match &session.worker {
+                Some(worker) => worker.clone(),
+                None => return vec![error_response(id, 24, "Unauthorized worker")],
+            };
+            let (job_id, hash, obj) = match (
+                request.params.get(1).and_then(|p| p.as_u64()),
+                request.params.get(2).and_then(|p| p.as_str()),
+                request.params.get(3).and_then(|p| p.as_str()),
+            ) {
+                (Some(job_id), Some(hash), Some(obj)) => (job_id, hash.to_string(), obj.to_string()),
+                _ => return vec![error_response(id, 20, "Expected [worker, job_id, hash, obj]")],
+            };
+
+            let mut messages = match ctx.push_to_pool(hash, obj, wallet.clone(), rig_name.clone(), job_id).await {
+                Ok(result) => vec![json!({ "id": id, "result": result, "error": null })],
+                Err(e) => vec![error_response(id, stratum_code(&e), &e.to_string())],
+            };
+
+            // Vardiff may have moved after this share, the rig needs work at its new difficulty
+            let cached = ctx.current_work().await.ok().flatten();
+            if let Some(mining_params) = cached {
+                let difficulty = ctx.rig_difficulty(&wallet, &rig_name, &mining_params).await.ok();
+                if session.difficulty != difficulty {
+                    messages.extend(work_messages(ctx, session, &mining_params).await);
+                }
+            }
+            messages
+        }
+        _ => vec![error_response(id, 20, "Unknown method")],
+    }
+}
+
+/// Builds the set_difficulty and notify messages for the session's rig
+async fn work_messages(ctx: &AppContex, session: &mut Session, mining_params: &MiningParams) -> Vec<JsonValue> {
+    let (wallet, rig_name) = match &session.worker {
+        Some(worker) => worker,
+        None => return Vec::new(),
+    };
+    let (difficulty, (job_id, work)) = match (
+        ctx.rig_difficulty(wallet, rig_name, mining_params).await,
+        ctx.encode_mining_params(mining_params, wallet, rig_name).await,
+    ) {
+        (Ok(difficulty), Ok(encoded)) => (difficulty, encoded),
+        (Err(e), _) | (_, Err(e)) => {
+            warn!(%wallet, rig = %rig_name, error = %e, "🚩 Work could not be sent");
+            return Vec::new();
+        }
+    };
+    session.difficulty = Some(difficulty);
+
+    vec![
+        json!({
+            "id": null,
+            "method": "mining.set_difficulty",
+            "params": [format!("{:x}", difficulty)],
+        }),
+        json!({
+            "id": null,
+            "method": "mining.notify",
+            "params": [job_id, work],
+        }),
+    ]
+}
+
+/// Maps proxy errors to the usual Stratum codes
+fn stratum_code(e: &ProxyError) -> i32 {
+    match e {
+        ProxyError::UnknownJob(_) | ProxyError::StaleJob(_) => 21,
+        ProxyError::Duplicate(_) => 22,
+        ProxyError::LowDifficulty { .. } => 23,
+        _ => 20,
+    }
+}
+
+fn error_response(id: JsonValue, code: i32, message: &str) -> JsonValue {
+    json!({
+        "id": id,
+        "result": null,
+        "error": [code, message, null],
+    })
+}
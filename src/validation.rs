@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use primitive_types::H256;
+use serde::Deserialize;
+use tokio::sync::oneshot;
+use tracing::warn;
+
+use crate::error::ProxyError;
+use crate::metrics;
+use crate::worker::{p3d_obj_hash, P3dParams};
+
+/// Objects waiting for a validation worker. Submissions beyond it are
+/// rejected as busy instead of piling up.
+pub const VALIDATION_QUEUE_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ValidationConfig {
+    /// Worker threads, one per core when unset
+    pub(crate) workers: Option<usize>,
+    pub(crate) queue_size: usize,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        ValidationConfig {
+            workers: None,
+            queue_size: VALIDATION_QUEUE_SIZE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Priority {
+    /// Likely block candidate, validated before any share
+    High,
+    Normal,
+}
+
+struct Task {
+    pre_hash: H256,
+    parent_hash: H256,
+    obj: Vec<u8>,
+    reply: oneshot::Sender<Option<H256>>,
+}
+
+#[derive(Default)]
+struct Queue {
+    high: VecDeque<Task>,
+    normal: VecDeque<Task>,
+}
+
+impl Queue {
+    fn len(&self) -> usize {
+        self.high.len() + self.normal.len()
+    }
+
+    fn pop(&mut self) -> Option<Task> {
+        self.high.pop_front().or_else(|| self.normal.pop_front())
+    }
+}
+
+/// Runs p3d_process on dedicated threads so the async runtime keeps
+/// serving work while objects are being validated.
+pub(crate) struct ValidationPool {
+    queue: Arc<(Mutex<Queue>, Condvar)>,
+    capacity: usize,
+}
+
+impl ValidationPool {
+    pub(crate) fn new(p3d_params: P3dParams, workers: usize, capacity: usize) -> anyhow::Result<Self> {
+        let queue: Arc<(Mutex<Queue>, Condvar)> = Arc::default();
+
+        for i in 0..workers.max(1) {
+            let queue = queue.clone();
+            let p3d_params = p3d_params.clone();
+            thread::Builder::new()
+                .name(format!("p3d-validation-{}", i))
+                .spawn(move || run_worker(&p3d_params, &queue))?;
+        }
+
+        Ok(ValidationPool { queue, capacity })
+    }
+
+    /// Hash of the object, None when it is not a valid 3D model or p3d panicked on it
+    pub(crate) async fn obj_hash(
+        &self,
+        priority: Priority,
+        pre_hash: H256,
+        parent_hash: H256,
+        obj: Vec<u8>,
+    ) -> Result<Option<H256>, ProxyError> {
+        let (reply, response) = oneshot::channel();
+        {
+            let (lock, available) = &*self.queue;
+            let mut queue = lock.lock().unwrap();
+            if queue.len() >= self.capacity {
+                return Err(ProxyError::Busy);
+            }
+            let task = Task {
+                pre_hash,
+                parent_hash,
+                obj,
+                reply,
+            };
+            match priority {
+                Priority::High => queue.high.push_back(task),
+                Priority::Normal => queue.normal.push_back(task),
+            }
+            available.notify_one();
+        }
+
+        response.await.map_err(|_| ProxyError::Busy)
+    }
+}
+
+fn run_worker(p3d_params: &P3dParams, queue: &(Mutex<Queue>, Condvar)) {
+    let (lock, available) = queue;
+    loop {
+        let task = {
+            let mut queue = lock.lock().unwrap();
+            loop {
+                match queue.pop() {
+                    Some(task) => break task,
+                    None => queue = available.wait(queue).unwrap(),
+                }
+            }
+        };
+
+        // The miner is gone, nobody is waiting for this one
+        if task.reply.is_closed() {
+            continue;
+        }
+
+        let started = Instant::now();
+        // p3d may panic on a malformed object, the worker has to outlive it
+        let obj_hash = panic::catch_unwind(AssertUnwindSafe(|| {
+            p3d_obj_hash(p3d_params, task.pre_hash, task.parent_hash, &task.obj)
+        }))
+        .unwrap_or_else(|_| {
+            warn!(pre_hash = ?task.pre_hash, "🚩 p3d_process panicked on an object");
+            None
+        });
+        metrics::p3d_process(started.elapsed());
+        let _ = task.reply.send(obj_hash);
+    }
+}
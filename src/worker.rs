@@ -1,17 +1,21 @@
 use codec::Encode;
 use hyper::Method;
 use jsonrpsee::server::{RpcModule, Server};
+use p3d::p3d_process;
 
 use primitive_types::{H256, U256};
 use serde::Serialize;
 use sha3::{Digest, Sha3_256};
 
 use std::net::SocketAddr;
+use std::str::FromStr;
 use std::sync::Arc;
 use tower_http::cors::{Any, CorsLayer};
 
 use crate::{
     pool_rpc::{PoolMiningRpcServer, PoolMiningRpcServerImpl},
+    solo_handler::SoloAppContex,
+    solo_rpc::{SoloMiningRpcServer, SoloMiningRpcServerImpl},
     stats_rpc::{StatsRpcServer, StatsRpcServerImpl},
 };
 
@@ -26,6 +30,13 @@ pub(crate) struct MiningParams {
     pub(crate) pub_key: ecies_ed25519::PublicKey,
 }
 
+#[derive(Clone, Default)]
+pub(crate) struct SoloMiningParams {
+    pub(crate) pre_hash: H256,
+    pub(crate) parent_hash: H256,
+    pub(crate) difficulty: U256,
+}
+
 #[derive(Clone)]
 pub(crate) struct DynamicMiningParams {
     pub(crate) dynamic_difficulty: U256,
@@ -65,6 +76,9 @@ pub(crate) struct MiningObj {
     pub(crate) obj: Vec<u8>,
 }
 
+/// Algorithms accepted by P3dParams::new
+pub(crate) const ALGORITHMS: &[&str] = &["grid2d", "grid2d_v2", "grid2d_v3", "grid2d_v3.1"];
+
 #[derive(Clone)]
 pub(crate) struct P3dParams {
     pub(crate) algo: AlgoType,
@@ -87,6 +101,27 @@ impl P3dParams {
     }
 }
 
+/// Runs p3d over the object and returns its first hash, rotated the way the node expects
+pub(crate) fn p3d_obj_hash(
+    p3d_params: &P3dParams,
+    pre_hash: H256,
+    parent_hash: H256,
+    obj: &[u8],
+) -> Option<H256> {
+    let P3dParams { algo, grid, sect } = p3d_params;
+    let rot_hash = match algo {
+        AlgoType::Grid2dV3_1 => pre_hash,
+        _ => parent_hash,
+    };
+
+    let rot = rot_hash.encode()[0..4].try_into().ok();
+
+    match p3d_process(obj, algo.as_p3d_algo(), *grid as i16, *sect as i16, rot) {
+        Ok(hashes) if !hashes.is_empty() => H256::from_str(&hashes[0]).ok(),
+        _ => None,
+    }
+}
+
 #[derive(Encode)]
 pub struct DoubleHash {
     pub pre_hash: H256,
@@ -137,7 +172,32 @@ pub(crate) async fn pool_rpc_server(ctx: Arc<AppContex>) -> anyhow::Result<Socke
     Ok(addr)
 }
 
-pub(crate) async fn run_stats_server(proxy_address: String) -> anyhow::Result<SocketAddr> {
+pub(crate) async fn solo_rpc_server(ctx: Arc<SoloAppContex>) -> anyhow::Result<SocketAddr> {
+    let cors = CorsLayer::new()
+        .allow_methods([Method::POST])
+        .allow_origin(Any)
+        .allow_headers([hyper::header::CONTENT_TYPE]);
+    let middleware = tower::ServiceBuilder::new().layer(cors);
+
+    let socker_url: SocketAddr = ctx.proxy_address.clone().parse::<SocketAddr>()?;
+    let server = Server::builder()
+        .set_middleware(middleware)
+        .build(socker_url)
+        .await?;
+
+    let mut module = RpcModule::new(ctx.clone());
+
+    module.merge(SoloMiningRpcServerImpl::new(ctx.clone()).into_rpc())?;
+
+    let addr = server.local_addr()?;
+    let handle = server.start(module);
+
+    tokio::spawn(handle.stopped());
+
+    Ok(addr)
+}
+
+pub(crate) async fn run_stats_server(proxy_address: String, ctx: Arc<AppContex>) -> anyhow::Result<SocketAddr> {
     let cors = CorsLayer::new()
         .allow_methods([Method::POST])
         .allow_origin(Any)
@@ -150,9 +210,9 @@ pub(crate) async fn run_stats_server(proxy_address: String) -> anyhow::Result<So
         .build(socker_url)
         .await?;
 
-    let mut module = RpcModule::new(());
+    let mut module = RpcModule::new(ctx.clone());
 
-    module.merge(StatsRpcServerImpl::new().into_rpc())?;
+    module.merge(StatsRpcServerImpl::new(ctx.clone()).into_rpc())?;
 
     let addr = server.local_addr()?;
     let handle = server.start(module);